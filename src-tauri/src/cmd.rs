@@ -1,4 +1,5 @@
 pub mod converter;
 pub mod encrypt;
+pub mod keys;
 pub mod server;
 pub mod system;
@@ -1,127 +1,794 @@
-use tokio::fs::File;
-use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+use std::{
+  collections::HashMap,
+  path::{Component, Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, State};
+use tokio::{
+  fs::File,
+  io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter},
+};
+
+use crate::utils::{
+  container::{ContainerHeader, DecryptReader, EncryptWriter, EncryptedHeader, FileMetadata, DEFAULT_CHUNK_SIZE},
+  crypto::{
+    decrypt_name, derive_key_async, encrypt_name, generate_nonce_prefix, generate_salt, random_file_name, CryptoError,
+    KdfParams, KdfPreset, KEY_LEN, SALT_LEN,
+  },
+  files::{walk_files, AtomicFile, OverwritePolicy},
+  job::{spawn_crypto_job, CryptoJob, CryptoJobState},
+  keys::KeySource,
+};
+
+/// 加密文件的扩展名
+const ENCRYPTED_EXT: &str = "enc";
+/// 批量处理时写入输出目录的清单文件名
+const MANIFEST_NAME: &str = "rigel-manifest.json";
+/// 文件名最大字节数 (常见文件系统的 NAME_MAX), 加密后的名称超过时改用随机名称
+const MAX_NAME_LEN: usize = 255;
+
+/// 加密文件的结果
+#[derive(Serialize)]
+pub struct EncryptFileResult {
+  job_id: u64,
+  /// 实际的输出路径 (hide_name 时为随机文件名, overwrite 为 rename 时可能被改名)
+  output_path: String,
+}
 
-use tauri::{async_runtime, AppHandle, Emitter};
+/// 加密文件, 返回任务 ID 和实际的输出路径 <br>
+/// password: 密码字符串, 或密钥文件、系统密钥库等密钥来源引用 (见 KeySource), 其他命令相同 <br>
+/// kdf_preset: Argon2 强度预设, 默认 balanced <br>
+/// hide_name: 隐藏原始文件名, 输出写入 output_path 所在目录并使用随机文件名,
+/// 原始文件名、类型、大小和修改时间加密保存在文件头中, 可通过 read_encrypted_metadata 读取 <br>
+/// overwrite: 输出文件已存在时的处理方式, 默认返回错误
+#[tauri::command]
+pub async fn encrypt_file(
+  app: AppHandle,
+  input_path: String,
+  output_path: String,
+  password: KeySource,
+  kdf_preset: Option<KdfPreset>,
+  hide_name: Option<bool>,
+  overwrite: Option<OverwritePolicy>,
+) -> Result<EncryptFileResult, String> {
+  let secret = password.secret_async().await?;
+  let output_path = if hide_name.unwrap_or(false) {
+    let dir = Path::new(&output_path).parent().unwrap_or(Path::new(""));
+    dir.join(format!("{}.{}", random_file_name(), ENCRYPTED_EXT))
+  } else {
+    PathBuf::from(output_path)
+  };
+  let (output_path, allow_overwrite) = overwrite.unwrap_or_default().resolve(&output_path)?;
+
+  let output = AtomicFile::new(&output_path, allow_overwrite);
+  let job_id = spawn_crypto_job(&app, |job| async move {
+    let file_size = tokio::fs::metadata(&input_path).await.map_err(|e| e.to_string())?.len();
+
+    // 1. 生成随机盐值并派生密钥
+    let kdf = kdf_preset.unwrap_or_default().params();
+    let salt = generate_salt();
+    let key = derive_key_async(&secret, &salt, &kdf).await?;
+
+    // 2. 逐块加密, 每块附带认证标签, 写完后再重命名到输出路径
+    encrypt_to(Path::new(&input_path), output, kdf, salt, &key, |offset| {
+      job.emit_progress(offset, file_size)
+    })
+    .await?;
+
+    job.emit_percent(100.0);
+    Ok(())
+  });
 
-use crate::utils::crypto::{derive_key, encrypt_decrypt_at_offset, generate_salt, SALT_LEN};
+  Ok(EncryptFileResult { job_id, output_path: output_path.to_string_lossy().into_owned() })
+}
 
-/// 加密文件
+/// 解密文件, 自动识别容器格式和旧格式, 返回任务 ID <br>
+/// 在开始写出之前校验密码, 密码错误直接返回 CryptoError::WrongPassword <br>
+/// 文件带有明文 SHA-256 校验记录时, 解密完成后校验, 不一致则不会生成输出文件
 #[tauri::command]
-pub fn encrypt_file(app: AppHandle, input_path: String, output_path: String, password: String) -> Result<(), String> {
-  let app_handle = app.clone();
-  async_runtime::spawn(async move {
-    let process = async move {
-      let input_file = File::open(&input_path).await.map_err(|e| e.to_string())?;
-      let mut output_file = File::create(&output_path).await.map_err(|e| e.to_string())?;
-      let file_size = input_file.metadata().await.map_err(|e| e.to_string())?.len();
-
-      // 1. 生成随机盐值并写入文件头
-      let salt = generate_salt();
-      output_file.write_all(&salt).await.map_err(|e| e.to_string())?;
-
-      // 2. 派生密钥
-      let key = derive_key(&password, &salt);
-
-      let mut reader = BufReader::new(input_file);
-      let mut writer = BufWriter::new(output_file);
-
-      let mut buffer = [0u8; 64 * 1024];
-      let mut offset = 0u64;
-
-      while let Ok(n) = reader.read(&mut buffer).await {
-        if n == 0 {
-          break;
-        }
-
-        let chunk = &mut buffer[..n];
-        encrypt_decrypt_at_offset(chunk, offset, &key);
+pub async fn decrypt_file(
+  app: AppHandle,
+  input_path: String,
+  output_path: String,
+  password: KeySource,
+  overwrite: Option<OverwritePolicy>,
+) -> Result<u64, CryptoError> {
+  let secret = password.secret_async().await?;
+  let (output_path, allow_overwrite) = overwrite.unwrap_or_default().resolve(Path::new(&output_path))?;
 
-        writer.write_all(chunk).await.map_err(|e| e.to_string())?;
-        tokio::task::yield_now().await;
+  // 1. 读取文件头, 派生密钥并校验密码
+  let mut reader = DecryptReader::open(&input_path, &secret).await?;
 
-        offset += n as u64;
+  let output = AtomicFile::new(&output_path, allow_overwrite);
+  let job_id = spawn_crypto_job(&app, |job| async move {
+    let total = reader.header().plain_size();
 
-        if offset % (1024 * 1024) < 64 * 1024 || offset == file_size {
-          let progress = (offset as f64 / file_size as f64 * 100.0).round();
-          let _ = app_handle.emit("encrypt_progress", progress);
-        }
-      }
+    // 2. 逐块解密, 写完后再重命名到输出路径
+    decrypt_to(&mut reader, output, |offset| job.emit_progress(offset, total)).await?;
 
-      writer.flush().await.map_err(|e| e.to_string())?;
+    job.emit_percent(100.0);
+    Ok(())
+  });
 
-      let _ = app_handle.emit("encrypt_progress", 100.0);
+  Ok(job_id)
+}
 
-      Ok::<(), String>(())
-    };
+/// 取消加解密任务, 未写完的临时文件会被删除 <br>
+/// 返回 false 表示任务不存在或已经结束
+#[tauri::command]
+pub fn cancel_crypto_job(job_id: u64, state: State<'_, CryptoJobState>) -> bool {
+  state.cancel(job_id)
+}
 
-    if let Err(e) = process.await {
-      log::error!("Error encrypting file: {}", e);
-      let _ = app.emit("encrypt_error", e.to_string());
+/// 读取加密文件头中的原始文件元数据, 只解密文件头, 不解密正文 <br>
+/// 旧格式或未保存元数据的文件返回 None, 密码错误返回 CryptoError::WrongPassword
+#[tauri::command]
+pub async fn read_encrypted_metadata(
+  input_path: String,
+  password: KeySource,
+) -> Result<Option<FileMetadata>, CryptoError> {
+  let secret = password.secret_async().await?;
+  let mut file = File::open(&input_path).await.map_err(|e| e.to_string())?;
+  let header = EncryptedHeader::read_from(&mut file).await?;
+
+  match &header {
+    EncryptedHeader::Container(container) if container.metadata.is_some() => {
+      let key = header.unlock(&secret).await?;
+      Ok(container.metadata(&key)?)
     }
+    _ => Ok(None),
+  }
+}
+
+/// 将旧格式 (仅盐值 + 固定 nonce) 的加密文件升级为容器格式, 明文只在内存中流转, 返回任务 ID <br>
+/// 注意: 旧格式没有认证信息, 无法校验密码, 密码错误时会得到无法使用的输出文件, 原文件不会被修改
+#[tauri::command]
+pub async fn upgrade_encrypted_file(
+  app: AppHandle,
+  input_path: String,
+  output_path: String,
+  password: KeySource,
+  kdf_preset: Option<KdfPreset>,
+  overwrite: Option<OverwritePolicy>,
+) -> Result<u64, CryptoError> {
+  let secret = password.secret_async().await?;
+  let mut reader = DecryptReader::open(&input_path, &secret).await?;
+  if !reader.header().is_legacy() {
+    return Err(CryptoError::Other("file is already in the current format".to_string()));
+  }
+  let (output_path, allow_overwrite) = overwrite.unwrap_or_default().resolve(Path::new(&output_path))?;
+
+  let output = AtomicFile::new(&output_path, allow_overwrite);
+  let job_id = spawn_crypto_job(&app, |job| async move {
+    let plain_size = reader.header().plain_size();
+
+    // 使用新的盐值和随机 nonce 重新加密
+    let kdf = kdf_preset.unwrap_or_default().params();
+    let salt = generate_salt();
+    let key = derive_key_async(&secret, &salt, &kdf).await?;
+    let header = ContainerHeader::new(kdf, salt, generate_nonce_prefix(), plain_size, &key);
+
+    reencrypt_to(&mut reader, output, header, &key, |offset| {
+      job.emit_progress(offset, plain_size)
+    })
+    .await?;
+
+    job.emit_percent(100.0);
+    Ok(())
   });
 
-  Ok(())
+  Ok(job_id)
 }
 
-/// 解密文件
+/// 更换加密文件的密码, 旧密钥解密和新密钥加密在内存中逐块进行, 明文不会写入磁盘, 返回任务 ID <br>
+/// 默认完成后原子替换原文件, 指定 output_path 时写入新文件; 同时支持旧格式文件 (会升级为容器格式),
+/// 但旧格式无法校验密码, 必须指定 output_path, 原文件不会被修改 <br>
+/// 文件头中的原始文件元数据会用新密钥重新加密; kdf_preset: 新密钥的 Argon2 强度预设, 默认 balanced
 #[tauri::command]
-pub async fn decrypt_file(
+pub async fn rekey_file(
   app: AppHandle,
   input_path: String,
-  output_path: String,
-  password: String,
+  old_password: KeySource,
+  new_password: KeySource,
+  output_path: Option<String>,
+  kdf_preset: Option<KdfPreset>,
+  overwrite: Option<OverwritePolicy>,
+) -> Result<u64, CryptoError> {
+  let old_secret = old_password.secret_async().await?;
+  let new_secret = new_password.secret_async().await?;
+
+  // 1. 校验旧密码, 读取原始文件元数据
+  let (mut reader, output, metadata) =
+    prepare_rekey(&input_path, &old_secret, output_path.as_deref(), overwrite).await?;
+
+  let job_id = spawn_crypto_job(&app, |job| async move {
+    let plain_size = reader.header().plain_size();
+
+    // 2. 使用新密码、新盐值和新 nonce 派生密钥
+    let kdf = kdf_preset.unwrap_or_default().params();
+    let salt = generate_salt();
+    let key = derive_key_async(&new_secret, &salt, &kdf).await?;
+    let mut header = ContainerHeader::new(kdf, salt, generate_nonce_prefix(), plain_size, &key);
+    if let Some(metadata) = &metadata {
+      header = header.with_metadata(&key, metadata)?;
+    }
+
+    // 3. 逐块重新加密, 写完后替换原文件
+    reencrypt_to(&mut reader, output, header, &key, |offset| {
+      job.emit_progress(offset, plain_size)
+    })
+    .await?;
+
+    job.emit_percent(100.0);
+    Ok(())
+  });
+
+  Ok(job_id)
+}
+
+/// rekey_file 启动任务之前的准备: 用旧密码打开文件, 确定输出文件并读取原始文件元数据
+async fn prepare_rekey(
+  input_path: &str,
+  old_secret: &[u8],
+  output_path: Option<&str>,
+  overwrite: Option<OverwritePolicy>,
+) -> Result<(DecryptReader, AtomicFile, Option<FileMetadata>), CryptoError> {
+  let reader = DecryptReader::open(input_path, old_secret).await?;
+
+  let (output_path, allow_overwrite) = match output_path {
+    Some(path) => overwrite.unwrap_or_default().resolve(Path::new(path))?,
+    // 旧格式没有密钥校验值, 旧密码输错时只会解密出乱码, 原地替换会用乱码覆盖唯一的副本
+    None if reader.header().is_legacy() => {
+      return Err(CryptoError::Other(
+        "legacy files cannot be rekeyed in place, choose an output path or upgrade the file first".to_string(),
+      ));
+    }
+    None => (PathBuf::from(input_path), true),
+  };
+
+  let metadata = match reader.header() {
+    EncryptedHeader::Container(header) => header.metadata(reader.key())?,
+    EncryptedHeader::Legacy { .. } => None,
+  };
+  Ok((reader, AtomicFile::new(&output_path, allow_overwrite), metadata))
+}
+
+/// 将 reader 解密出的明文用新密钥重新加密写入 output, 明文只在内存中流转
+async fn reencrypt_to(
+  reader: &mut DecryptReader,
+  output: AtomicFile,
+  header: ContainerHeader,
+  key: &[u8; KEY_LEN],
+  mut progress: impl FnMut(u64),
+) -> Result<(), String> {
+  let mut writer = EncryptWriter::create(output.create().await?, header, *key).await?;
+
+  let mut buffer = Vec::with_capacity(DEFAULT_CHUNK_SIZE as usize);
+  while reader.read_chunk(&mut buffer).await? {
+    writer.write(&buffer).await?;
+
+    tokio::task::yield_now().await;
+    progress(reader.offset());
+  }
+
+  writer.finish().await?;
+  output.commit().await
+}
+
+/// 将明文文件加密为容器格式, progress 回调参数为已加密的明文字节数
+async fn encrypt_to(
+  input_path: &Path,
+  output: AtomicFile,
+  kdf: KdfParams,
+  salt: [u8; SALT_LEN],
+  key: &[u8; KEY_LEN],
+  mut progress: impl FnMut(u64),
 ) -> Result<(), String> {
-  let app_handle = app.clone();
+  let input_file = File::open(input_path).await.map_err(|e| e.to_string())?;
+  let output_file = output.create().await?;
+  let file_size = input_file.metadata().await.map_err(|e| e.to_string())?.len();
 
-  async_runtime::spawn(async move {
-    let process = async move {
-      let mut input_file = File::open(&input_path).await.map_err(|e| e.to_string())?;
-      let output_file = File::create(&output_path).await.map_err(|e| e.to_string())?;
-      let file_size = input_file.metadata().await.map_err(|e| e.to_string())?.len();
+  // 文件头包含随机 nonce、密钥校验值和加密的原始文件元数据
+  let metadata = FileMetadata::from_path(input_path)?;
+  let header =
+    ContainerHeader::new(kdf, salt, generate_nonce_prefix(), file_size, key).with_metadata(key, &metadata)?;
 
-      // 1. 读取盐值
-      let mut salt = [0u8; SALT_LEN];
-      input_file.read_exact(&mut salt).await.map_err(|e: std::io::Error| e.to_string())?;
+  let mut reader = BufReader::new(input_file);
+  let mut writer = EncryptWriter::create(output_file, header, *key).await?;
 
-      // 2. 派生密钥
-      let key = derive_key(&password, &salt);
+  let mut buffer = vec![0u8; DEFAULT_CHUNK_SIZE as usize];
+  let mut offset = 0u64;
 
-      let mut reader = BufReader::new(input_file);
-      let mut writer = BufWriter::new(output_file);
+  loop {
+    let n = reader.read(&mut buffer).await.map_err(|e| format!("failed to read input file: {}", e))?;
+    if n == 0 {
+      break;
+    }
 
-      let mut buffer = [0u8; 64 * 1024];
-      let mut offset = 0u64;
+    writer.write(&buffer[..n]).await?;
+    tokio::task::yield_now().await;
 
-      while let Ok(n) = reader.read(&mut buffer).await {
-        if n == 0 {
-          break;
-        }
+    offset += n as u64;
+    progress(offset);
+  }
 
-        let chunk = &mut buffer[..n];
-        encrypt_decrypt_at_offset(chunk, offset, &key);
+  writer.finish().await?;
+  output.commit().await
+}
+
+/// 将 reader 解密出的明文写入 output, progress 回调参数为已解密的明文字节数
+async fn decrypt_to(
+  reader: &mut DecryptReader,
+  output: AtomicFile,
+  mut progress: impl FnMut(u64),
+) -> Result<(), String> {
+  let mut writer = BufWriter::new(output.create().await?);
+
+  let mut buffer = Vec::with_capacity(DEFAULT_CHUNK_SIZE as usize);
+  while reader.read_chunk(&mut buffer).await? {
+    writer.write_all(&buffer).await.map_err(|e| e.to_string())?;
 
-        writer.write_all(chunk).await.map_err(|e| e.to_string())?;
+    tokio::task::yield_now().await;
+    progress(reader.offset());
+  }
 
-        offset += n as u64;
+  writer.flush().await.map_err(|e| e.to_string())?;
+  drop(writer);
+  output.commit().await
+}
+
+/// 批量处理清单中的一项
+#[derive(Serialize)]
+struct ManifestEntry {
+  /// 输入相对路径, 启用文件名加密时不记录明文文件名
+  source: Option<String>,
+  /// 输出相对路径
+  output: Option<String>,
+  size: u64,
+  error: Option<String>,
+}
 
-        tokio::task::yield_now().await;
-        if offset % (1024 * 1024) < 64 * 1024 || offset == file_size {
-          let progress = (offset as f64 / file_size as f64 * 100.0).round();
-          let _ = app_handle.emit("encrypt_progress", progress);
+/// 批量处理清单, 写入输出目录的 rigel-manifest.json
+#[derive(Serialize)]
+struct FolderManifest {
+  version: u8,
+  mode: &'static str,
+  created_at: String,
+  encrypt_names: bool,
+  succeeded: usize,
+  failed: usize,
+  files: Vec<ManifestEntry>,
+  /// 加密后超过 MAX_NAME_LEN 的文件名和目录名: 实际使用的随机名称 -> 加密名称
+  long_names: HashMap<String, String>,
+}
+
+/// 解密文件夹时从清单中读取的部分
+#[derive(Default, Deserialize)]
+struct ManifestNames {
+  /// 加密时是否加密了文件名, 没有清单时为 None
+  encrypt_names: Option<bool>,
+  #[serde(default)]
+  long_names: HashMap<String, String>,
+}
+
+impl ManifestNames {
+  /// 读取输入目录中的清单, 没有清单 (例如单独复制的文件) 时返回空表, 清单损坏时返回错误
+  async fn read_from(dir: &Path) -> Result<Self, CryptoError> {
+    let Ok(data) = tokio::fs::read(dir.join(MANIFEST_NAME)).await else {
+      return Ok(Self::default());
+    };
+    serde_json::from_slice(&data).map_err(|e| CryptoError::Other(format!("failed to parse {}: {}", MANIFEST_NAME, e)))
+  }
+
+  /// 还原加密的文件名或目录名 <br>
+  /// 清单记录了加密文件名时, 解密失败直接返回错误; 记录了未加密时原样返回;
+  /// 没有清单时无法区分, 能解密就解密, 否则原样返回 <br>
+  /// 过长而改用随机名称的, 先通过清单中的 long_names 找到加密名称 <br>
+  /// 解密出的名称来自文件内容, 必须是单个普通路径分量 (不能为空、'.'、'..', 不能包含分隔符或根路径),
+  /// 否则可能写到输出目录之外
+  fn restore_name(&self, key: &[u8; KEY_LEN], name: &str) -> Result<String, CryptoError> {
+    let encrypted = self.long_names.get(name).map(String::as_str).unwrap_or(name);
+    let restored = match self.encrypt_names {
+      Some(true) => decrypt_name(key, encrypted)
+        .ok_or_else(|| CryptoError::Other(format!("failed to decrypt file name: {}", name)))?,
+      Some(false) => name.to_string(),
+      None => decrypt_name(key, encrypted).unwrap_or_else(|| name.to_string()),
+    };
+
+    let mut components = Path::new(&restored).components();
+    let is_single_normal = matches!((components.next(), components.next()), (Some(Component::Normal(_)), None));
+    if !is_single_normal || restored.contains(['/', '\\']) {
+      return Err(CryptoError::Other(format!(
+        "invalid file name in encrypted folder: {:?}",
+        restored
+      )));
+    }
+    Ok(restored)
+  }
+}
+
+impl FolderManifest {
+  fn new(mode: &'static str, encrypt_names: bool) -> Self {
+    Self {
+      version: 1,
+      mode,
+      created_at: chrono::Local::now().to_rfc3339(),
+      encrypt_names,
+      succeeded: 0,
+      failed: 0,
+      files: Vec::new(),
+      long_names: HashMap::new(),
+    }
+  }
+
+  /// 加密文件名或目录名, suffix 为加密名称后追加的扩展名 <br>
+  /// 加密名称约为原名称的 4/3 倍再加上 54 个字符, 原名称超过约 140 字节时会超出文件名长度上限,
+  /// 这时改用随机名称, 并把加密名称记录在清单中, 解密时据此还原
+  fn encrypt_name(&mut self, key: &[u8; KEY_LEN], name: &str, suffix: &str) -> Result<String, String> {
+    let encrypted = encrypt_name(key, name)?;
+    if encrypted.len() + suffix.len() <= MAX_NAME_LEN {
+      return Ok(format!("{}{}", encrypted, suffix));
+    }
+    let random = random_file_name();
+    self.long_names.insert(random.clone(), encrypted);
+    Ok(format!("{}{}", random, suffix))
+  }
+
+  fn push(&mut self, entry: ManifestEntry) {
+    if entry.error.is_some() {
+      self.failed += 1;
+    } else {
+      self.succeeded += 1;
+    }
+    self.files.push(entry);
+  }
+
+  async fn write_to(&self, dir: &Path) -> Result<PathBuf, String> {
+    let path = dir.join(MANIFEST_NAME);
+    let data = serde_json::to_vec_pretty(self).map_err(|e| e.to_string())?;
+    tokio::fs::write(&path, data).await.map_err(|e| e.to_string())?;
+    Ok(path)
+  }
+}
+
+/// 批量处理完成事件数据结构
+#[derive(Clone, Serialize)]
+struct FolderCompletePayload {
+  job_id: u64,
+  succeeded: usize,
+  failed: usize,
+  manifest_path: String,
+}
+
+/// 批量任务的总体进度, 百分比变化时才通过 encrypt_progress 发送
+struct BatchProgress {
+  job: CryptoJob,
+  total: u64,
+  done: u64,
+  last: f64,
+}
+
+impl BatchProgress {
+  fn new(job: CryptoJob, total: u64) -> Self {
+    Self { job, total, done: 0, last: -1.0 }
+  }
+
+  /// offset: 当前文件已处理的字节数
+  fn update(&mut self, offset: u64) {
+    if self.total == 0 {
+      return;
+    }
+    let progress = ((self.done + offset) as f64 / self.total as f64 * 100.0).floor().min(100.0);
+    if progress > self.last {
+      self.last = progress;
+      self.job.emit_percent(progress);
+    }
+  }
+
+  fn finish_file(&mut self, size: u64) {
+    self.done += size;
+    self.update(0);
+  }
+}
+
+/// 将相对路径转换为 '/' 分隔的字符串, 用于清单记录
+fn display_relative(path: &Path) -> String {
+  path.components().map(|c| c.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/")
+}
+
+/// 检查输入输出目录, 输出目录不存在时创建, 并且不能位于输入目录内
+fn prepare_folders(input_dir: &str, output_dir: &str) -> Result<(PathBuf, PathBuf), String> {
+  let input = Path::new(input_dir).canonicalize().map_err(|e| format!("{}: {}", input_dir, e))?;
+  if !input.is_dir() {
+    return Err(format!("{} is not a directory", input_dir));
+  }
+
+  std::fs::create_dir_all(output_dir).map_err(|e| e.to_string())?;
+  let output = Path::new(output_dir).canonicalize().map_err(|e| e.to_string())?;
+  if output.starts_with(&input) {
+    return Err("output folder must not be inside the input folder".to_string());
+  }
+
+  Ok((input, output))
+}
+
+/// 批量加密文件夹, 保留目录结构, 每个文件输出为 .enc 容器文件 <br>
+/// encrypt_names: 是否同时加密文件名和目录名 <br>
+/// 整个文件夹只派生一次密钥 (共享盐值), 每个文件仍使用独立的随机 nonce, 可以用 decrypt_file 单独解密
+#[tauri::command]
+pub async fn encrypt_folder(
+  app: AppHandle,
+  input_dir: String,
+  output_dir: String,
+  password: KeySource,
+  encrypt_names: Option<bool>,
+  kdf_preset: Option<KdfPreset>,
+  overwrite: Option<OverwritePolicy>,
+) -> Result<u64, String> {
+  let secret = password.secret_async().await?;
+  let (input, output) = prepare_folders(&input_dir, &output_dir)?;
+  let overwrite = overwrite.unwrap_or_default();
+  let encrypt_names = encrypt_names.unwrap_or(false);
+  let job_id = spawn_crypto_job(&app, |job| async move {
+    let files = walk_files(&input)?;
+    let sizes: Vec<u64> = files.iter().map(|f| f.metadata().map(|m| m.len()).unwrap_or(0)).collect();
+    let mut progress = BatchProgress::new(job.clone(), sizes.iter().sum());
+
+    let kdf = kdf_preset.unwrap_or_default().params();
+    let salt = generate_salt();
+    let key = derive_key_async(&secret, &salt, &kdf).await?;
+
+    let mut manifest = FolderManifest::new("encrypt", encrypt_names);
+    // 同一目录的加密名称需要保持一致
+    let mut dir_names: HashMap<PathBuf, PathBuf> = HashMap::new();
+
+    for (file, size) in files.iter().zip(sizes) {
+      let relative = file.strip_prefix(&input).map_err(|e| e.to_string())?;
+
+      let result = async {
+        let out_relative = if encrypt_names {
+          let parent = relative.parent().unwrap_or(Path::new(""));
+          let mut out_dir = PathBuf::new();
+          let mut current = PathBuf::new();
+          for component in parent.components() {
+            current.push(component);
+            let name = match dir_names.get(&current) {
+              Some(name) => name.clone(),
+              None => {
+                let name = PathBuf::from(manifest.encrypt_name(&key, &component.as_os_str().to_string_lossy(), "")?);
+                dir_names.insert(current.clone(), name.clone());
+                name
+              }
+            };
+            out_dir.push(name);
+          }
+          let file_name = relative.file_name().unwrap_or_default().to_string_lossy();
+          out_dir.join(manifest.encrypt_name(&key, &file_name, &format!(".{}", ENCRYPTED_EXT))?)
+        } else {
+          let mut name = relative.as_os_str().to_os_string();
+          name.push(format!(".{}", ENCRYPTED_EXT));
+          PathBuf::from(name)
+        };
+
+        let output_path = output.join(&out_relative);
+        if let Some(parent) = output_path.parent() {
+          tokio::fs::create_dir_all(parent).await.map_err(|e| e.to_string())?;
         }
+
+        let (output_path, allow_overwrite) = overwrite.resolve(&output_path)?;
+        let output_file = AtomicFile::new(&output_path, allow_overwrite);
+        encrypt_to(file, output_file, kdf, salt, &key, |offset| progress.update(offset)).await?;
+        Ok::<PathBuf, String>(output_path.strip_prefix(&output).unwrap_or(&output_path).to_path_buf())
+      }
+      .await;
+
+      if let Err(e) = &result {
+        log::error!("Error encrypting {}: {}", file.display(), e);
       }
+      progress.finish_file(size);
+
+      manifest.push(ManifestEntry {
+        source: (!encrypt_names).then(|| display_relative(relative)),
+        output: result.as_ref().ok().map(|p| display_relative(p)),
+        size,
+        error: result.err(),
+      });
+    }
 
-      writer.flush().await.map_err(|e| e.to_string())?;
-      let _ = app_handle.emit("encrypt_progress", 100.0);
+    let manifest_path = manifest.write_to(&output).await?;
+    job.emit_percent(100.0);
+    job.emit(
+      "encrypt_folder_complete",
+      FolderCompletePayload {
+        job_id: job.id,
+        succeeded: manifest.succeeded,
+        failed: manifest.failed,
+        manifest_path: manifest_path.to_string_lossy().into_owned(),
+      },
+    );
+
+    Ok(())
+  });
 
-      Ok::<(), String>(())
-    };
+  Ok(job_id)
+}
 
-    if let Err(e) = process.await {
-      log::error!("Error decrypting file: {}", e);
-      let _ = app.emit("encrypt_error", e.to_string());
+/// 批量解密文件夹中的 .enc 文件, 保留目录结构, 自动还原加密的文件名 <br>
+/// 在开始之前用第一个文件校验密码, 密码错误直接返回 CryptoError::WrongPassword
+#[tauri::command]
+pub async fn decrypt_folder(
+  app: AppHandle,
+  input_dir: String,
+  output_dir: String,
+  password: KeySource,
+  overwrite: Option<OverwritePolicy>,
+) -> Result<u64, CryptoError> {
+  let secret = password.secret_async().await?;
+  let (input, output) = prepare_folders(&input_dir, &output_dir)?;
+  let overwrite = overwrite.unwrap_or_default();
+
+  let files: Vec<PathBuf> =
+    walk_files(&input)?.into_iter().filter(|f| f.extension().is_some_and(|ext| ext == ENCRYPTED_EXT)).collect();
+  let names = ManifestNames::read_from(&input).await?;
+
+  // 批量加密的文件共享盐值, 按 (盐值, KDF 参数) 缓存密钥, 避免重复执行 Argon2
+  let mut keys: HashMap<([u8; SALT_LEN], KdfParams), [u8; KEY_LEN]> = HashMap::new();
+  if let Some(first) = files.first() {
+    let mut file = File::open(first).await.map_err(|e| e.to_string())?;
+    let header = EncryptedHeader::read_from(&mut file).await?;
+    keys.insert((*header.salt(), header.kdf()), header.unlock(&secret).await?);
+  }
+
+  let job_id = spawn_crypto_job(&app, |job| async move {
+    let sizes: Vec<u64> = files.iter().map(|f| f.metadata().map(|m| m.len()).unwrap_or(0)).collect();
+    let mut progress = BatchProgress::new(job.clone(), sizes.iter().sum());
+    let mut manifest = FolderManifest::new("decrypt", false);
+
+    for (file, size) in files.iter().zip(sizes) {
+      let relative = file.strip_prefix(&input).map_err(|e| e.to_string())?;
+
+      let result = async {
+        let mut input_file = File::open(file).await.map_err(|e| e.to_string())?;
+        let header = EncryptedHeader::read_from(&mut input_file).await?;
+
+        let cache_key = (*header.salt(), header.kdf());
+        let key = match keys.get(&cache_key) {
+          Some(key) => *key,
+          None => {
+            let key = header.unlock(&secret).await?;
+            keys.insert(cache_key, key);
+            key
+          }
+        };
+        header.verify_key(&key)?;
+
+        // 还原目录名和文件名
+        let mut out_relative = PathBuf::new();
+        if let Some(parent) = relative.parent() {
+          for component in parent.components() {
+            out_relative.push(names.restore_name(&key, &component.as_os_str().to_string_lossy())?);
+          }
+        }
+        let stem = relative.file_stem().unwrap_or_default().to_string_lossy();
+        out_relative.push(names.restore_name(&key, &stem)?);
+
+        let output_path = output.join(&out_relative);
+        if let Some(parent) = output_path.parent() {
+          tokio::fs::create_dir_all(parent).await.map_err(|e| e.to_string())?;
+        }
+
+        let (output_path, allow_overwrite) = overwrite.resolve(&output_path)?;
+        let mut reader = DecryptReader::new(input_file, header, key);
+        decrypt_to(&mut reader, AtomicFile::new(&output_path, allow_overwrite), |offset| {
+          progress.update(offset)
+        })
+        .await?;
+        Ok::<PathBuf, String>(output_path.strip_prefix(&output).unwrap_or(&output_path).to_path_buf())
+      }
+      .await;
+
+      if let Err(e) = &result {
+        log::error!("Error decrypting {}: {}", file.display(), e);
+      }
+      progress.finish_file(size);
+
+      manifest.push(ManifestEntry {
+        source: Some(display_relative(relative)),
+        output: result.as_ref().ok().map(|p| display_relative(p)),
+        size,
+        error: result.err(),
+      });
     }
+
+    let manifest_path = manifest.write_to(&output).await?;
+    job.emit_percent(100.0);
+    job.emit(
+      "encrypt_folder_complete",
+      FolderCompletePayload {
+        job_id: job.id,
+        succeeded: manifest.succeeded,
+        failed: manifest.failed,
+        manifest_path: manifest_path.to_string_lossy().into_owned(),
+      },
+    );
+
+    Ok(())
   });
 
-  Ok(())
+  Ok(job_id)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::utils::crypto::{derive_key, encrypt_decrypt_at_offset, LEGACY_NONCE};
+
+  const PASSWORD: &[u8] = b"correct horse battery staple";
+  const WRONG_PASSWORD: &[u8] = b"wrong password";
+
+  #[tokio::test]
+  async fn legacy_rekey_with_wrong_password_keeps_original() {
+    let dir = std::env::temp_dir().join(format!("rigel_rekey_{}", random_file_name()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    // 旧格式: 盐值 + ChaCha20 流密文, 固定使用默认 KDF 参数
+    let input = dir.join("legacy.enc");
+    let salt = generate_salt();
+    let key = derive_key(PASSWORD, &salt, &KdfParams::default()).unwrap();
+    let mut data = b"legacy plaintext ".repeat(1000);
+    encrypt_decrypt_at_offset(&mut data, 0, &key, LEGACY_NONCE);
+    let original = [salt.as_slice(), &data].concat();
+    std::fs::write(&input, &original).unwrap();
+    let input_path = input.to_string_lossy().into_owned();
+
+    // 原地更换密码直接被拒绝
+    assert!(prepare_rekey(&input_path, WRONG_PASSWORD, None, None).await.is_err());
+    assert_eq!(std::fs::read(&input).unwrap(), original);
+
+    // 写入新文件时输出是乱码, 但原文件保持不变
+    let output = dir.join("rekeyed.enc");
+    let (mut reader, output_file, _) =
+      prepare_rekey(&input_path, WRONG_PASSWORD, Some(&output.to_string_lossy()), None).await.unwrap();
+    let kdf = KdfParams { m_cost: 64, t_cost: 1, p_cost: 1 };
+    let new_salt = generate_salt();
+    let new_key = derive_key(b"new password", &new_salt, &kdf).unwrap();
+    let header = ContainerHeader::new(kdf, new_salt, generate_nonce_prefix(), reader.header().plain_size(), &new_key);
+    reencrypt_to(&mut reader, output_file, header, &new_key, |_| {}).await.unwrap();
+
+    assert!(output.exists());
+    assert_eq!(std::fs::read(&input).unwrap(), original);
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn restore_name_fails_hard_when_names_are_encrypted() {
+    let key = [3u8; KEY_LEN];
+    let encrypted = encrypt_name(&key, "holiday.mp4").unwrap();
+    let long = encrypt_name(&key, &"long name ".repeat(30)).unwrap();
+    let names = |encrypt_names: Option<bool>| ManifestNames {
+      encrypt_names,
+      long_names: HashMap::from([
+        ("random".to_string(), long.clone()),
+        ("corrupted".to_string(), long[..long.len() - 4].to_string()),
+      ]),
+    };
+
+    let strict = names(Some(true));
+    assert_eq!(strict.restore_name(&key, &encrypted).unwrap(), "holiday.mp4");
+    assert_eq!(strict.restore_name(&key, "random").unwrap(), "long name ".repeat(30));
+    assert!(strict.restore_name(&key, "corrupted").is_err());
+    assert!(strict.restore_name(&key, "plain.mp4").is_err());
+    assert!(strict.restore_name(&[4u8; KEY_LEN], &encrypted).is_err());
+
+    // 未加密文件名时原样返回, 没有清单时解密失败也原样返回
+    assert_eq!(names(Some(false)).restore_name(&key, "plain.mp4").unwrap(), "plain.mp4");
+    assert_eq!(names(None).restore_name(&key, "plain.mp4").unwrap(), "plain.mp4");
+    assert_eq!(names(None).restore_name(&key, &encrypted).unwrap(), "holiday.mp4");
+
+    for bad in ["..", ".", "", "a/b", "/etc"] {
+      let encrypted = encrypt_name(&key, bad).unwrap();
+      assert!(strict.restore_name(&key, &encrypted).is_err(), "{:?}", bad);
+    }
+  }
 }
@@ -0,0 +1,19 @@
+use crate::utils::keys;
+
+/// 将密码保存到系统密钥库, 之后可以用 { type: "keyring", account } 代替密码
+#[tauri::command]
+pub fn save_keyring_password(account: String, password: String) -> Result<(), String> {
+  keys::save_keyring_password(&account, &password)
+}
+
+/// 从系统密钥库删除保存的密码
+#[tauri::command]
+pub fn delete_keyring_password(account: String) -> Result<(), String> {
+  keys::delete_keyring_password(&account)
+}
+
+/// 生成随机密钥文件 (64 字节), 之后可以用 { type: "keyFile", path } 代替密码
+#[tauri::command]
+pub fn generate_key_file(path: String) -> Result<(), String> {
+  keys::generate_key_file(&path)
+}
@@ -1,47 +1,318 @@
-use std::{path::PathBuf, sync::Mutex};
+use std::{
+  path::PathBuf,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+  },
+  time::Duration,
+};
 
-use tauri::State;
+use serde::Serialize;
+use tauri::{async_runtime, AppHandle, Emitter, Manager, State};
 use tokio::sync::oneshot;
+use tokio_util::sync::CancellationToken;
 
-use crate::utils::server::start_server;
+use crate::utils::{
+  crypto::{random_token, CryptoError},
+  keys::KeySource,
+  server::{start_server, ServerConfig, StreamEntry, StreamRegistry, ACCESS_TOKEN_PARAM},
+  stats::{now_millis, StreamStatsSnapshot},
+};
 
+/// 默认空闲超时: 30 分钟没有请求后关闭服务器
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+/// 空闲检查间隔
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// 运行中的流媒体服务器
+struct RunningServer {
+  port: u16,
+  config: Arc<ServerConfig>,
+  shutdown_tx: oneshot::Sender<()>,
+  /// 停止该服务器的空闲检查
+  idle_watch: CancellationToken,
+}
+
+impl RunningServer {
+  /// 流地址, 带上本次会话的访问令牌
+  fn stream_url(&self, token: &str) -> String {
+    format!(
+      "http://127.0.0.1:{}/stream/{}?{}={}",
+      self.port, token, ACCESS_TOKEN_PARAM, self.config.access_token
+    )
+  }
+
+  /// HLS 播放列表地址, 第一次请求时才开始切片
+  fn hls_url(&self, token: &str) -> String {
+    format!(
+      "http://127.0.0.1:{}/hls/{}/index.m3u8?{}={}",
+      self.port, token, ACCESS_TOKEN_PARAM, self.config.access_token
+    )
+  }
+
+  /// 实时转码地址, 需要从指定时间开始时追加 &t=秒数
+  fn transcode_url(&self, token: &str) -> String {
+    format!(
+      "http://127.0.0.1:{}/transcode/{}?{}={}",
+      self.port, token, ACCESS_TOKEN_PARAM, self.config.access_token
+    )
+  }
+
+  fn stream_info(&self, token: &str, entry: &StreamEntry) -> StreamInfo {
+    StreamInfo {
+      token: token.to_string(),
+      url: self.stream_url(token),
+      hls_url: self.hls_url(token),
+      transcode_url: self.transcode_url(token),
+      path: entry.path.to_string_lossy().into_owned(),
+    }
+  }
+}
+
+/// 全局唯一的流媒体服务器, 首次注册流时启动, 之后所有流共用
 pub struct ServerState {
-  pub shutdown_tx: Mutex<Option<oneshot::Sender<()>>>,
+  server: tokio::sync::Mutex<Option<RunningServer>>,
+  streams: StreamRegistry,
+  /// start_video_stream 注册的流, 再次调用时替换
+  default_token: Mutex<Option<String>>,
+  /// 是否校验 Origin, 对之后启动的服务器同样生效
+  check_origin: Mutex<bool>,
+  /// 空闲超时, None 表示不自动关闭
+  idle_timeout: Mutex<Option<Duration>>,
+  /// 最后一次注册流的时间 (Unix 毫秒), 刚注册还没开始播放的流不算空闲
+  last_register: AtomicU64,
+}
+
+impl Default for ServerState {
+  fn default() -> Self {
+    Self {
+      server: Default::default(),
+      streams: Default::default(),
+      default_token: Default::default(),
+      check_origin: Default::default(),
+      idle_timeout: Mutex::new(Some(DEFAULT_IDLE_TIMEOUT)),
+      last_register: Default::default(),
+    }
+  }
+}
+
+impl ServerState {
+  /// 服务器未运行时启动, 返回流信息
+  async fn register(&self, app: &AppHandle, token: String, entry: StreamEntry) -> Result<StreamInfo, String> {
+    let mut server = self.server.lock().await;
+    if server.is_none() {
+      let config = Arc::new(ServerConfig {
+        access_token: random_token(),
+        check_origin: (*self.check_origin.lock().unwrap()).into(),
+      });
+      let (shutdown_tx, shutdown_rx) = oneshot::channel();
+      let port = start_server(app.clone(), self.streams.clone(), config.clone(), shutdown_rx).await?;
+      let idle_watch = CancellationToken::new();
+      spawn_idle_watch(app.clone(), idle_watch.clone());
+      *server = Some(RunningServer { port, config, shutdown_tx, idle_watch });
+    }
+
+    let info = server.as_ref().unwrap().stream_info(&token, &entry);
+    self.streams.write().unwrap().insert(token, Arc::new(entry));
+    self.last_register.store(now_millis(), Ordering::Relaxed);
+    Ok(info)
+  }
+
+  /// 距离最后一次请求、数据传输 (或注册流) 的毫秒数 <br>
+  /// 不看活动连接数: 暂停的播放器会一直保持连接, 这正是需要超时清除密钥的情况
+  fn idle_millis(&self) -> u64 {
+    let last = self
+      .streams
+      .read()
+      .unwrap()
+      .values()
+      .map(|entry| entry.stats.last_activity())
+      .fold(self.last_register.load(Ordering::Relaxed), u64::max);
+    now_millis().saturating_sub(last)
+  }
+
+  /// 注销流, 并结束这个流上仍在传输的响应
+  fn revoke(&self, token: &str) -> bool {
+    let entry = self.streams.write().unwrap().remove(token);
+    entry.map(|entry| entry.close()).is_some()
+  }
+
+  /// 注销所有流并关闭服务器 <br>
+  /// 流中缓存的密钥是 Zeroizing 的, 最后一个引用释放时内存被清零; 仍在传输的响应会被结束,
+  /// 否则响应中持有的密钥副本会一直留在内存中
+  async fn shutdown(&self) {
+    for (_, entry) in self.streams.write().unwrap().drain() {
+      entry.close();
+    }
+    *self.default_token.lock().unwrap() = None;
+    if let Some(server) = self.server.lock().await.take() {
+      server.idle_watch.cancel();
+      let _ = server.shutdown_tx.send(());
+    }
+  }
+}
+
+/// stream_server_stopped 事件数据结构
+#[derive(Clone, Serialize)]
+struct ServerStoppedPayload {
+  reason: String,
+}
+
+/// 定时检查服务器是否空闲, 超时后关闭服务器并发送 stream_server_stopped 事件
+fn spawn_idle_watch(app: AppHandle, cancel: CancellationToken) {
+  async_runtime::spawn(async move {
+    let idle = cancel
+      .run_until_cancelled(async {
+        loop {
+          tokio::time::sleep(IDLE_CHECK_INTERVAL).await;
+          let state = app.state::<ServerState>();
+          let Some(timeout) = *state.idle_timeout.lock().unwrap() else {
+            continue;
+          };
+          if state.idle_millis() >= timeout.as_millis() as u64 {
+            break;
+          }
+        }
+      })
+      .await;
+
+    // 关闭过程不能放在 run_until_cancelled 里, shutdown 会取消令牌
+    if idle.is_some() && !cancel.is_cancelled() {
+      log::info!("Streaming server idle, shutting down");
+      app.state::<ServerState>().shutdown().await;
+      let _ = app.emit("stream_server_stopped", ServerStoppedPayload { reason: "idle".to_string() });
+    }
+  });
+}
+
+/// 已注册的流信息
+#[derive(Serialize)]
+pub struct StreamInfo {
+  token: String,
+  url: String,
+  /// HLS 地址, 用于 moov 在文件末尾等无法直接边下边播的视频
+  hls_url: String,
+  /// 实时转码地址, 用于 webview 无法解码的编码 (HEVC 等)
+  transcode_url: String,
+  path: String,
 }
 
+/// 注册一个加密媒体流 (视频、音频或图片), 返回流地址 <br>
+/// 每个流有独立的随机令牌和密钥, 可以同时播放多个视频; 地址中带有本次会话的访问令牌, 不带令牌的请求返回 401
 #[tauri::command]
-pub async fn start_video_stream(
-  password: String,
+pub async fn register_stream(
+  app: AppHandle,
+  password: KeySource,
   path: String,
   state: State<'_, ServerState>,
-) -> Result<String, String> {
-  let video_path = PathBuf::from(path);
+) -> Result<StreamInfo, CryptoError> {
+  let video_path = PathBuf::from(&path);
 
   if !video_path.exists() {
-    return Err("Video file not found".to_string());
+    return Err(CryptoError::Other("Video file not found".to_string()));
   }
 
-  let (tx, rx) = oneshot::channel();
-  {
-    let mut tx_guard = state.shutdown_tx.lock().unwrap();
-    if let Some(old_tx) = tx_guard.take() {
-      let _ = old_tx.send(());
-    }
-    *tx_guard = Some(tx);
+  // 注册时校验密码并派生密钥, 避免播放器拿到一个无法播放的数据流; 之后的请求直接使用缓存的密钥
+  let secret = password.secret_async().await?;
+  let entry = StreamEntry::open(video_path, Some(&secret)).await?;
+
+  let token = random_token();
+  Ok(state.register(&app, token, entry).await?)
+}
+
+/// 注册一个未加密的本地媒体文件, 返回流地址 <br>
+/// 与加密流使用同一个服务器和同样的 Range 支持, 用于播放 webview 无法通过 asset 协议直接读取的文件 (网络共享、路径含特殊字符等)
+#[tauri::command]
+pub async fn register_plain_stream(
+  app: AppHandle,
+  path: String,
+  state: State<'_, ServerState>,
+) -> Result<StreamInfo, String> {
+  let media_path = PathBuf::from(&path);
+
+  if !media_path.is_file() {
+    return Err("Media file not found".to_string());
+  }
+
+  let entry = StreamEntry::open(media_path, None).await.map_err(|e| e.to_string())?;
+
+  let token = random_token();
+  state.register(&app, token, entry).await
+}
+
+/// 列出已注册的流
+#[tauri::command]
+pub async fn list_streams(state: State<'_, ServerState>) -> Result<Vec<StreamInfo>, String> {
+  let server = state.server.lock().await;
+  let Some(server) = server.as_ref() else {
+    return Ok(Vec::new());
+  };
+
+  let streams = state.streams.read().unwrap();
+  Ok(streams.iter().map(|(token, entry)| server.stream_info(token, entry)).collect())
+}
+
+/// 获取各个流的访问统计 (请求数、发送字节数、活动连接数、最后访问时间) <br>
+/// 服务器运行时还会每秒发送 stream_stats 事件, 附带发送速率
+#[tauri::command]
+pub fn get_stream_stats(state: State<'_, ServerState>) -> Vec<StreamStatsSnapshot> {
+  state.streams.read().unwrap().iter().map(|(token, entry)| entry.stats.snapshot(token)).collect()
+}
+
+/// 注销流, 之后该地址返回 404 <br>
+/// 返回 false 表示流不存在
+#[tauri::command]
+pub fn revoke_stream(token: String, state: State<'_, ServerState>) -> bool {
+  state.revoke(&token)
+}
+
+/// 注销所有流并关闭流媒体服务器, 下次注册流时重新启动
+#[tauri::command]
+pub async fn stop_stream_server(state: State<'_, ServerState>) -> Result<(), String> {
+  state.shutdown().await;
+  Ok(())
+}
+
+/// 设置是否校验请求的 Origin, 开启后拒绝来自普通网页 (非本应用 webview) 的请求
+#[tauri::command]
+pub async fn set_stream_origin_check(enabled: bool, state: State<'_, ServerState>) -> Result<(), String> {
+  *state.check_origin.lock().unwrap() = enabled;
+  if let Some(server) = state.server.lock().await.as_ref() {
+    server.config.check_origin.store(enabled, Ordering::Relaxed);
   }
+  Ok(())
+}
 
-  let port = start_server(password, video_path, rx).await;
+/// 设置空闲超时 (分钟), 超过该时间没有请求时自动关闭服务器并清除内存中的密钥, 0 表示不自动关闭
+#[tauri::command]
+pub fn set_stream_idle_timeout(minutes: u64, state: State<'_, ServerState>) {
+  *state.idle_timeout.lock().unwrap() = (minutes > 0).then(|| Duration::from_secs(minutes * 60));
+}
+
+/// 兼容接口: 注册一个流并替换上一次 start_video_stream 注册的流
+#[tauri::command]
+pub async fn start_video_stream(
+  app: AppHandle,
+  password: KeySource,
+  path: String,
+  state: State<'_, ServerState>,
+) -> Result<String, CryptoError> {
+  let info = register_stream(app, password, path, state.clone()).await?;
+
+  let previous = state.default_token.lock().unwrap().replace(info.token.clone());
+  if let Some(previous) = previous {
+    state.revoke(&previous);
+  }
 
-  Ok(format!("http://127.0.0.1:{}/video", port))
+  Ok(info.url)
 }
 
+/// 兼容接口: 注销 start_video_stream 注册的流
 #[tauri::command]
 pub async fn stop_video_stream(state: State<'_, ServerState>) -> Result<String, String> {
-  let mut tx_guard = state.shutdown_tx.lock().unwrap();
-  if let Some(tx) = tx_guard.take() {
-    let _ = tx.send(());
-    Ok("Server stopped".to_string())
-  } else {
-    Ok("No server running".to_string())
+  let token = state.default_token.lock().unwrap().take();
+  match token {
+    Some(token) if state.revoke(&token) => Ok("Stream stopped".to_string()),
+    _ => Ok("No stream running".to_string()),
   }
 }
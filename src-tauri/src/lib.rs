@@ -4,7 +4,7 @@ pub mod shell;
 pub mod utils;
 
 use chrono::{FixedOffset, Utc};
-use std::sync::{Mutex, OnceLock};
+use std::sync::OnceLock;
 use tauri::{
   menu::{Menu, MenuItem},
   tray::{MouseButton, TrayIconBuilder, TrayIconEvent},
@@ -13,7 +13,11 @@ use tauri::{
 
 use tauri_plugin_log::{Target, TargetKind};
 
-use crate::cmd::server::ServerState;
+use crate::{
+  cmd::server::ServerState,
+  shell::queue::{spawn_ffmpeg_scheduler, FfmpegQueue},
+  utils::job::CryptoJobState,
+};
 
 /// 程序文件缓存路径
 static FILE_PATH: OnceLock<String> = OnceLock::new();
@@ -41,7 +45,8 @@ pub fn run() {
         let _ = w.set_focus();
       });
     }))
-    .manage(ServerState { shutdown_tx: Mutex::new(None) })
+    .manage(ServerState::default())
+    .manage(CryptoJobState::default())
     .on_window_event(|window, event| {
       if let WindowEvent::CloseRequested { api, .. } = event {
         api.prevent_close();
@@ -49,6 +54,10 @@ pub fn run() {
       }
     })
     .setup(|app| {
+      // ffmpeg 任务队列, 恢复上次未完成的任务
+      app.manage(FfmpegQueue::load(app.handle()));
+      spawn_ffmpeg_scheduler(app.handle().clone());
+
       // 1. 创建菜单项
       // 参数: manager, id, text, enabled, accelerator
       let quit_i = MenuItem::with_id(app, "quit", "退出", true, None::<&str>)?;
@@ -130,13 +139,37 @@ pub fn run() {
       cmd::system::get_gpu_info,
       cmd::encrypt::encrypt_file,
       cmd::encrypt::decrypt_file,
+      cmd::encrypt::upgrade_encrypted_file,
+      cmd::encrypt::rekey_file,
+      cmd::keys::save_keyring_password,
+      cmd::keys::delete_keyring_password,
+      cmd::keys::generate_key_file,
+      cmd::encrypt::read_encrypted_metadata,
+      cmd::encrypt::cancel_crypto_job,
+      cmd::encrypt::encrypt_folder,
+      cmd::encrypt::decrypt_folder,
+      cmd::server::register_stream,
+      cmd::server::register_plain_stream,
+      cmd::server::list_streams,
+      cmd::server::get_stream_stats,
+      cmd::server::revoke_stream,
+      cmd::server::stop_stream_server,
+      cmd::server::set_stream_origin_check,
+      cmd::server::set_stream_idle_timeout,
       cmd::server::start_video_stream,
       cmd::server::stop_video_stream,
       shell::ffmpeg::convert_video_to_mp4,
       shell::ffmpeg::create_highlight_video,
       shell::ffmpeg::merge_smart,
       shell::ffmpeg::append_smart,
-      shell::ffmpeg::get_video_info
+      shell::ffmpeg::get_video_info,
+      shell::queue::enqueue_ffmpeg_job,
+      shell::queue::list_ffmpeg_jobs,
+      shell::queue::reorder_ffmpeg_job,
+      shell::queue::pause_ffmpeg_queue,
+      shell::queue::resume_ffmpeg_queue,
+      shell::queue::cancel_ffmpeg_job,
+      shell::queue::set_ffmpeg_max_concurrency
     ])
     .run(tauri::generate_context!())
     .expect("error while running tauri application");
@@ -1 +1,4 @@
 pub mod ffmpeg;
+pub mod ffprobe;
+pub mod progress;
+pub mod queue;
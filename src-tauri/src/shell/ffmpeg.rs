@@ -1,27 +1,110 @@
 use std::io::Write;
-use std::{fs::File, path::Path};
+use std::{
+  collections::{HashMap, VecDeque},
+  fs::File,
+  path::Path,
+};
 
-use regex::Regex;
 use serde::{Deserialize, Serialize};
-use tauri::{AppHandle, Emitter};
-use tauri_plugin_shell::{process::CommandEvent, ShellExt};
+use tauri::{async_runtime::Receiver, AppHandle, Emitter};
+use tauri_plugin_shell::process::CommandEvent;
 
-use crate::utils::files::get_cache_temp_dir;
+use crate::shell::ffprobe::probe_media;
+use crate::shell::progress::{FfmpegProgress, ProgressParser};
+use crate::shell::queue::{enqueue_and_wait, FfmpegJobContext, FfmpegJobKind};
 use crate::utils::font::get_default_font_path;
-use crate::utils::{
-  files::get_cache_dir,
-  gpu::{get_gpu_info, GpuInfo},
-};
+use crate::utils::gpu::{get_gpu_info, GpuInfo};
+
+// 所有耗时的 ffmpeg 命令都通过 shell::queue 中的任务队列执行, 由队列控制并发数
+
+/// ffmpeg 失败时随错误返回的 stderr 行数
+const STDERR_TAIL_LINES: usize = 20;
+/// stderr 中出现这些内容时说明编码器不可用 (未编译进 ffmpeg, 或显卡驱动不支持)
+const ENCODER_ERROR_PATTERNS: [&str; 5] = [
+  "Unknown encoder",
+  "Error while opening encoder",
+  "No NVENC capable devices found",
+  "Cannot load nvcuda",
+  "Cannot load libcuda",
+];
+
+/// ffmpeg/ffprobe 错误, 序列化为 { kind, ... } 传给前端
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "camelCase", rename_all_fields = "camelCase")]
+pub enum FfmpegError {
+  /// 输入文件不存在
+  InputMissing { path: String },
+  /// ffprobe 无法读取媒体信息
+  ProbeFailed { path: String, message: String },
+  /// 选择的编码器不可用, stderr 为 ffmpeg 最后几行输出
+  EncoderUnavailable { stderr: Vec<String> },
+  /// 写入输出文件时磁盘空间不足
+  DiskFull { path: String },
+  /// ffmpeg 非 0 退出, stderr 为最后几行输出
+  Exited { code: Option<i32>, stderr: Vec<String> },
+  /// 任务被取消
+  Cancelled,
+  /// 其他错误 (IO、sidecar 启动失败、参数错误等)
+  Other { message: String },
+}
 
-// HACK 后续可以将ffmpeg封装成一个结构体, 生成一个任务队列, 并有序的执行ffmpeg命令
-// 任务也是一个结构体包含命令集合、任务状态等信息
-// 最后在主线程中循环处理任务队列
-// 前端调用方法将任务注入任务队列, 前端直接展示整个任务列表以及任务进度等
-// 这样前端可以更好的控制任务队列
+impl std::fmt::Display for FfmpegError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      FfmpegError::InputMissing { path } => write!(f, "input file not found: {}", path),
+      FfmpegError::ProbeFailed { path, message } => write!(f, "failed to probe {}: {}", path, message),
+      FfmpegError::EncoderUnavailable { stderr } => {
+        write!(
+          f,
+          "encoder unavailable: {}",
+          stderr.last().map(String::as_str).unwrap_or_default()
+        )
+      }
+      FfmpegError::DiskFull { path } => write!(f, "no space left on device while writing {}", path),
+      FfmpegError::Exited { code, stderr } => match code {
+        Some(code) => write!(f, "ffmpeg exited with status {}: {}", code, stderr.join("\n")),
+        None => write!(f, "ffmpeg was terminated: {}", stderr.join("\n")),
+      },
+      FfmpegError::Cancelled => write!(f, "ffmpeg job cancelled"),
+      FfmpegError::Other { message } => write!(f, "{}", message),
+    }
+  }
+}
+
+impl From<String> for FfmpegError {
+  fn from(message: String) -> Self {
+    FfmpegError::Other { message }
+  }
+}
+
+impl From<FfmpegError> for String {
+  fn from(e: FfmpegError) -> Self {
+    e.to_string()
+  }
+}
+
+impl FfmpegError {
+  /// 根据 stderr 内容归类 ffmpeg 的非 0 退出
+  fn from_exit(code: Option<i32>, stderr: Vec<String>, output_path: &str) -> Self {
+    let contains = |pattern: &str| stderr.iter().any(|line| line.contains(pattern));
+
+    if contains("No space left on device") {
+      FfmpegError::DiskFull { path: output_path.to_string() }
+    } else if ENCODER_ERROR_PATTERNS.iter().any(|pattern| contains(pattern)) {
+      FfmpegError::EncoderUnavailable { stderr }
+    } else {
+      FfmpegError::Exited { code, stderr }
+    }
+  }
+}
 
+/// 媒体信息, 由 ffprobe 读取 <br>
+/// width、height、fps 和编码等字段取自第一个视频流和音频流, 纯音频文件时视频相关字段为 0 或空
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct VideoInfo {
   pub path: String,
+  /// 显示宽度, 旋转 90/270 度的视频已交换宽高
   pub width: u32,
   pub height: u32,
   pub fps: f64,
@@ -30,24 +113,116 @@ pub struct VideoInfo {
   pub audio_codec: String,
   pub audio_sample_rate: u32,
   pub bitrate_kbps: u32,
+  /// 视频显示时需要顺时针旋转的角度 (0/90/180/270)
+  pub rotation: u32,
+  pub pix_fmt: Option<String>,
+  /// 容器格式, 如 "mov,mp4,m4a,3gp,3g2,mj2"
+  pub format_name: String,
+  pub format_long_name: String,
+  /// 文件大小 (字节)
+  pub size: u64,
+  /// 容器标签, 如 title、encoder、creation_time
+  pub tags: HashMap<String, String>,
+  /// 所有流, 包括视频、音频、字幕和附件
+  pub streams: Vec<MediaStream>,
+  pub chapters: Vec<MediaChapter>,
+}
+
+/// 流类型
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum MediaStreamKind {
+  Video,
+  Audio,
+  Subtitle,
+  Attachment,
+  Data,
+  #[default]
+  #[serde(other)]
+  Unknown,
+}
+
+/// 单个流的信息, 视频、音频和附件专有的字段在其他类型的流中为 None
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MediaStream {
+  pub index: u32,
+  pub kind: MediaStreamKind,
+  pub codec_name: String,
+  pub codec_long_name: String,
+  pub profile: Option<String>,
+  pub bitrate_kbps: Option<u32>,
+  pub duration: Option<f64>,
+  pub language: Option<String>,
+  pub title: Option<String>,
+  pub is_default: bool,
+  /// 封面图片 (mp3/m4a 等文件中以视频流形式存在)
+  pub is_attached_pic: bool,
+  pub tags: HashMap<String, String>,
+  // 视频
+  pub width: Option<u32>,
+  pub height: Option<u32>,
+  pub fps: Option<f64>,
+  pub rotation: u32,
+  pub pix_fmt: Option<String>,
+  pub color_range: Option<String>,
+  pub color_space: Option<String>,
+  pub color_transfer: Option<String>,
+  pub color_primaries: Option<String>,
+  // 音频
+  pub sample_rate: Option<u32>,
+  pub channels: Option<u32>,
+  pub channel_layout: Option<String>,
+  // 附件 (如 mkv 中的字体)
+  pub file_name: Option<String>,
+  pub mime_type: Option<String>,
+}
+
+/// 章节信息, 时间单位为秒
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MediaChapter {
+  pub id: i64,
+  pub start: f64,
+  pub end: f64,
+  pub title: Option<String>,
+  pub tags: HashMap<String, String>,
 }
 
 /// 进度事件数据结构
 #[derive(Clone, Serialize, Deserialize)]
 pub struct ProgressPayload {
+  pub job_id: u64,           // 队列中的任务 id
   pub progress: f64,         // 进度百分比 (0-100)
   pub video_info: VideoInfo, // 视频总时长（秒）
   pub message: String,       // 进度消息
+  pub speed: Option<f64>,    // 编码速度倍数, 1.0 为实时
+  pub eta: Option<f64>,      // 预计剩余时间（秒）
+  pub stats: FfmpegProgress, // ffmpeg 原始进度
+}
+
+impl ProgressPayload {
+  /// 根据进度块和本次 ffmpeg 输出的总时长计算百分比和剩余时间
+  fn new(ctx: &FfmpegJobContext, stats: FfmpegProgress, duration: f64, video_info: VideoInfo, message: String) -> Self {
+    let progress = if duration > 0.0 { (stats.out_time / duration * 100.0).min(100.0) } else { 0.0 };
+    let eta = match stats.speed {
+      Some(speed) if speed > 0.0 && duration > 0.0 => Some((duration - stats.out_time).max(0.0) / speed),
+      _ => None,
+    };
+
+    Self { job_id: ctx.id, progress, video_info, message, speed: stats.speed, eta, stats }
+  }
 }
 
 /// 完成事件数据结构
 #[derive(Clone, Serialize, Deserialize)]
 struct CompletionPayload {
+  job_id: u64,
   code: Option<i32>,
 }
 
 /// 事件数据结构
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TimeSegment {
   pub start: String,    // 格式 "00:00:10.000" 或 秒数 "10"
   pub duration: String, // 格式 "00:00:20.000" 或 秒数 "20"
@@ -55,7 +230,7 @@ pub struct TimeSegment {
 
 /// 编码器预设
 #[derive(Debug)]
-enum EncoderPreset {
+pub(crate) enum EncoderPreset {
   Nvidia(String), // hevc_nvenc
   Intel(String),  // hevc_qsv
   Amd(String),    // hevc_amf
@@ -131,6 +306,24 @@ impl EncoderPreset {
 
     [&common_args[..], &encoder_args[..]].concat()
   }
+
+  /// 实时转码的 FFmpeg 参数: 与 to_ffmpeg_args 使用同一厂商的硬件, 但输出 webview 都能解码的 H.264/AAC <br>
+  /// 优先考虑编码速度, 质量参数比离线转码宽松
+  pub(crate) fn to_live_ffmpeg_args(&self) -> Vec<&'static str> {
+    let common_args = vec!["-c:a", "aac", "-ac", "2", "-b:a", "160k", "-pix_fmt", "yuv420p"];
+
+    let encoder_args = match self {
+      EncoderPreset::Nvidia(_) => vec!["-c:v", "h264_nvenc", "-preset", "p2", "-tune", "ll", "-cq", "23"],
+      EncoderPreset::Intel(_) => vec!["-c:v", "h264_qsv", "-preset", "veryfast", "-global_quality", "23"],
+      EncoderPreset::Amd(_) => {
+        vec!["-c:v", "h264_amf", "-usage", "lowlatency", "-rc", "cqp", "-qp_i", "23", "-qp_p", "23"]
+      }
+      EncoderPreset::Apple(_) => vec!["-c:v", "h264_videotoolbox", "-q:v", "60", "-realtime", "1"],
+      EncoderPreset::Cpu(_) => vec!["-c:v", "libx264", "-preset", "veryfast", "-tune", "zerolatency", "-crf", "23"],
+    };
+
+    [&common_args[..], &encoder_args[..]].concat()
+  }
 }
 
 /// 匹配厂商到编码器预设
@@ -152,7 +345,7 @@ fn match_vendor_to_encoder(name: &str) -> EncoderPreset {
 }
 
 /// 选择最佳编码器
-fn select_best_encoder(gpus: &[GpuInfo]) -> EncoderPreset {
+pub(crate) fn select_best_encoder(gpus: &[GpuInfo]) -> EncoderPreset {
   // 1. 优先寻找独立显卡 (DiscreteGpu)
   if let Some(gpu) = gpus.iter().find(|g| g.device_type == "DiscreteGpu") {
     return match_vendor_to_encoder(&gpu.name);
@@ -167,22 +360,6 @@ fn select_best_encoder(gpus: &[GpuInfo]) -> EncoderPreset {
   EncoderPreset::Cpu("libx265".to_string())
 }
 
-/// 从 FFmpeg 输出解析当前时间（秒）
-fn parse_time_from_ffmpeg_output(line: &Vec<u8>) -> Option<f64> {
-  let line_str = String::from_utf8_lossy(&line);
-
-  // 查找 time= 格式的时间（如 time=00:01:23.45）
-  if let Some(start) = line_str.find("time=") {
-    let time_part = &line_str[start + 5..];
-    let end = time_part.find(' ').unwrap_or(time_part.len());
-    let time_str = &time_part[..end];
-
-    parse_duration_str(time_str)
-  } else {
-    None
-  }
-}
-
 /// 用于计算多个视频合并后的目标参数 <br>
 /// return: (目标宽度, 目标高度, 目标帧率)
 fn calculate_target_params(videos_info: &[VideoInfo]) -> (u32, u32, f64) {
@@ -193,7 +370,7 @@ fn calculate_target_params(videos_info: &[VideoInfo]) -> (u32, u32, f64) {
   // B. 计算帧率中位数 (取大者)
   let mut fps_list: Vec<f64> = videos_info.iter().map(|m| m.fps).collect();
   // 排序
-  fps_list.sort_by(|a, b| a.partial_cmp(b).unwrap());
+  fps_list.sort_by(|a, b| a.total_cmp(b));
 
   let len = fps_list.len();
   let median_fps = if len == 0 {
@@ -208,7 +385,7 @@ fn calculate_target_params(videos_info: &[VideoInfo]) -> (u32, u32, f64) {
 }
 
 /// 将 HH:MM:SS.ms 转换为秒
-fn parse_duration_str(duration_str: &str) -> Option<f64> {
+pub(crate) fn parse_duration_str(duration_str: &str) -> Option<f64> {
   let parts: Vec<&str> = duration_str.split(':').collect();
   match parts.len() {
     4 => {
@@ -235,107 +412,72 @@ fn parse_duration_str(duration_str: &str) -> Option<f64> {
   }
 }
 
-/// 获取视频信息
-#[tauri::command]
-pub async fn get_video_info(app: AppHandle, video_path: &str) -> Result<VideoInfo, String> {
-  let shell = app.shell();
-  let (mut rx, _child) = shell
-    .sidecar("ffmpeg")
-    .map_err(|e| format!("Failed to create sidecar: {}", e))?
-    .args(&["-i", video_path, "-hide_banner"])
-    .spawn()
-    .map_err(|e| e.to_string())?;
-  let mut video_info = VideoInfo { path: video_path.to_string(), ..Default::default() };
-
-  // 正则表达式
-  // 匹配分辨率: 寻找 "Video:" 行后面的数字x数字
-  let re_res = Regex::new(r"Video:.*?\s(\d{3,})x(\d{3,})").unwrap();
-
-  // 匹配 FPS: 寻找 "23.98 fps" 或 "60 fps"
-  let re_fps = Regex::new(r",\s*(\d+(?:\.\d+)?)\s*fps").unwrap();
-
-  // 匹配时长和总码率: "Duration: 00:00:10.50, start: 0.000000, bitrate: 5466 kb/s"
-  let re_dur_bitrate = Regex::new(r"Duration:\s*(\d{2}:\d{2}:\d{2}\.\d{2}).*?bitrate:\s*(\d+)\s*kb/s").unwrap();
-
-  // 匹配视频编码: "Stream #0:0(eng): Video: hevc (Main)..." -> 捕获 "hevc"
-  // 逻辑：匹配 "Video:" 后面的第一个单词
-  let re_vcodec = Regex::new(r"Video:\s*([a-zA-Z0-9_]+)").unwrap();
-
-  // 匹配音频编码和采样率: "Stream #0:1: Audio: aac (LC), 48000 Hz, stereo..."
-  // 逻辑：匹配 "Audio:" 后面的单词，以及后面的 Hz 数值
-  let re_acodec = Regex::new(r"Audio:\s*([a-zA-Z0-9_]+).*?(\d+)\s*Hz").unwrap();
-
-  // 完全异步处理，不使用 block_on
-  while let Some(event) = rx.recv().await {
-    match event {
-      CommandEvent::Stderr(data) => {
-        let line = String::from_utf8_lossy(&data).trim().to_string();
-
-        // 1. 解析基础视频信息 (分辨率, 编码)
-        if line.contains("Video:") {
-          if let Some(caps) = re_res.captures(&line) {
-            video_info.width = caps[1].parse().unwrap_or(0);
-            video_info.height = caps[2].parse().unwrap_or(0);
-          }
-          if let Some(caps) = re_fps.captures(&line) {
-            video_info.fps = caps[1].parse().unwrap_or(0.0);
-          }
-          // 防止覆盖，只获取第一个视频流的编码
-          if video_info.video_codec.is_empty() {
-            if let Some(caps) = re_vcodec.captures(&line) {
-              video_info.video_codec = caps[1].to_string();
-            }
-          }
-        }
+/// 检测显卡并选择编码器, 检测失败时使用 CPU 编码
+async fn best_encoder() -> EncoderPreset {
+  let gpus = get_gpu_info().await.unwrap_or_else(|e| {
+    log::warn!("failed to detect gpu, fallback to cpu encoder: {}", e);
+    Vec::new()
+  });
+  select_best_encoder(&gpus)
+}
 
-        // 2. 解析音频信息
-        if line.contains("Audio:") && video_info.audio_codec.is_empty() {
-          if let Some(caps) = re_acodec.captures(&line) {
-            video_info.audio_codec = caps[1].to_string();
-            video_info.audio_sample_rate = caps[2].parse().unwrap_or(0);
-          }
-        }
+/// 等待 ffmpeg 结束 <br>
+/// stdout 中的进度块交给 on_progress, stderr 只保留最后几行, 非 0 退出时返回对应的错误
+async fn wait_ffmpeg(
+  mut rx: Receiver<CommandEvent>,
+  output_path: &str,
+  mut on_progress: impl FnMut(FfmpegProgress),
+) -> Result<(), FfmpegError> {
+  let mut parser = ProgressParser::default();
+  let mut stderr = VecDeque::with_capacity(STDERR_TAIL_LINES);
 
-        // 3. 解析时长和码率
-        if line.contains("Duration:") {
-          if let Some(caps) = re_dur_bitrate.captures(&line) {
-            // 解析时长字符串 (HH:MM:SS.mm)
-            if let Some(duration) = parse_duration_str(&caps[1]) {
-              video_info.duration = duration;
-            }
-            // 解析码率
-            video_info.bitrate_kbps = caps[2].parse().unwrap_or(0);
-          }
+  while let Some(event) = rx.recv().await {
+    let line = match event {
+      CommandEvent::Stdout(line) => {
+        if let Some(stats) = parser.feed_line(&line) {
+          on_progress(stats);
         }
+        continue;
       }
+      CommandEvent::Stderr(line) => String::from_utf8_lossy(&line).trim().to_string(),
+      CommandEvent::Error(e) => e,
       CommandEvent::Terminated(status) => {
-        if let Some(code) = status.code {
-          if code == 0 || code == 1 {
-            break;
-          }
-          return Err(format!("ffmpeg exited with status {}", code));
+        if status.code == Some(0) {
+          return Ok(());
         }
-        break;
+        return Err(FfmpegError::from_exit(status.code, stderr.into(), output_path));
+      }
+      _ => continue,
+    };
+
+    if !line.is_empty() {
+      if stderr.len() == STDERR_TAIL_LINES {
+        stderr.pop_front();
       }
-      _ => {}
+      stderr.push_back(line);
     }
   }
 
-  // 以视频时长为准
-  if video_info.duration > 0.0 {
-    return Ok(video_info);
-  }
+  Err(FfmpegError::Exited { code: None, stderr: stderr.into() })
+}
 
-  Err("cannot get video duration".to_string())
+/// 取文件名, 用于进度消息和 drawtext
+fn file_name_of(path: &str) -> String {
+  Path::new(path).file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_else(|| path.to_string())
 }
 
-/// 将视频转换成 mp4 格式
+/// 获取视频信息
 #[tauri::command]
-pub async fn convert_video_to_mp4(app: AppHandle, video_path: &str, output_path: &str) -> Result<(), String> {
-  let video_info = get_video_info(app.clone(), video_path).await.unwrap();
+pub async fn get_video_info(app: AppHandle, video_path: &str) -> Result<VideoInfo, FfmpegError> {
+  probe_media(&app, video_path).await
+}
+
+/// 将视频转换成 mp4 格式
+async fn run_convert(ctx: &FfmpegJobContext, video_path: &str, output_path: &str) -> Result<(), FfmpegError> {
+  let app = ctx.app.clone();
+  let video_info = probe_media(&app, video_path).await?;
 
-  let gpus = get_gpu_info().await.unwrap();
-  let best = select_best_encoder(&gpus);
+  let best = best_encoder().await;
   let best_args = best.to_ffmpeg_args();
 
   let mut args = Vec::with_capacity(5 + best_args.len());
@@ -347,60 +489,41 @@ pub async fn convert_video_to_mp4(app: AppHandle, video_path: &str, output_path:
   args.push(output_path);
   args.push("-hide_banner");
 
-  // 获取 shell 实例
-  let shell = app.shell();
-
   // 创建命令（注意：这里的 "ffmpeg" 必须在 capabilities 中配置）
   log::info!("ffmpeg {}", args.join(" "));
-  let (mut rx, _child) = shell
-    .sidecar("ffmpeg")
-    .map_err(|e| format!("Failed to create sidecar: {}", e))?
-    .args(args)
-    .spawn()
-    .map_err(|e| e.to_string())?;
+  let rx = ctx.spawn_ffmpeg(args)?;
 
   // 异步处理输出流，不要使用 block_on
-  while let Some(event) = rx.recv().await {
-    match event {
-      CommandEvent::Stderr(line) => {
-        if let Some(current_time) = parse_time_from_ffmpeg_output(&line) {
-          // 发射进度事件到前端
-          let _ = app.emit(
-            "ffmpeg-progress",
-            ProgressPayload {
-              progress: if video_info.duration > 0.0 { (current_time / video_info.duration) * 100.0 } else { 0.0 },
-              video_info: video_info.clone(),
-              message: format!("transcoding from {} to {}", video_path, output_path),
-            },
-          );
-        }
-      }
-      CommandEvent::Terminated(status) => {
-        let _ = app.emit("ffmpeg-complete", CompletionPayload { code: status.code });
-        break;
-      }
-      _ => {}
-    }
-  }
-
-  return Ok(());
+  wait_ffmpeg(rx, output_path, |stats| {
+    // 发射进度事件到前端
+    ctx.emit_progress(ProgressPayload::new(
+      ctx,
+      stats,
+      video_info.duration,
+      video_info.clone(),
+      format!("transcoding from {} to {}", video_path, output_path),
+    ));
+  })
+  .await?;
+
+  let _ = app.emit("ffmpeg-complete", CompletionPayload { job_id: ctx.id, code: Some(0) });
+  Ok(())
 }
 
 /// 裁剪和合并视频，来截取精彩的片段
-#[tauri::command]
-pub async fn create_highlight_video(
-  app: AppHandle,
+async fn run_highlight(
+  ctx: &FfmpegJobContext,
   video_path: &str,
   output_path: &str,
-  segments: Vec<TimeSegment>,
-) -> Result<(), String> {
-  let video_info = get_video_info(app.clone(), video_path).await.unwrap();
+  segments: &[TimeSegment],
+) -> Result<(), FfmpegError> {
+  let app = ctx.app.clone();
+  let video_info = probe_media(&app, video_path).await?;
 
   let mut temp_files = Vec::new();
-  let temp_dir = get_cache_temp_dir(app.clone())?;
+  let temp_dir = ctx.temp_dir()?;
 
-  let gpus = get_gpu_info().await.unwrap();
-  let best = select_best_encoder(&gpus);
+  let best = best_encoder().await;
   let best_args = best.to_ffmpeg_args();
 
   for (i, seg) in segments.iter().enumerate() {
@@ -420,45 +543,30 @@ pub async fn create_highlight_video(
     args.push(&temp_name);
     args.push("-hide_banner");
 
-    let duration = parse_duration_str(&seg.duration).unwrap();
+    let duration = parse_duration_str(&seg.duration)
+      .or_else(|| seg.duration.parse().ok())
+      .ok_or_else(|| format!("invalid segment duration: {}", seg.duration))?;
 
     log::info!("ffmpeg {}", args.join(" "));
-    let shell = app.shell();
-    let (mut rx, _child) = shell
-      .sidecar("ffmpeg")
-      .map_err(|e| format!("Failed to create sidecar: {}", e))?
-      .args(args)
-      .spawn()
-      .map_err(|e| e.to_string())?;
-    while let Some(event) = rx.recv().await {
-      match event {
-        CommandEvent::Stderr(data) => {
-          if let Some(current_time) = parse_time_from_ffmpeg_output(&data) {
-            // 发射进度事件到前端
-            let _ = app.emit(
-              "ffmpeg-progress",
-              ProgressPayload {
-                progress: if duration > 0.0 { (current_time / duration) * 100.0 } else { 0.0 },
-                video_info: video_info.clone(),
-                message: format!("split segment {}: {}", seg.start, seg.duration),
-              },
-            );
-          }
-        }
-        CommandEvent::Terminated(status) => {
-          if status.code == Some(0) {
-            temp_files.push(temp_name);
-          }
-          let _ = app.emit("ffmpeg-complete", CompletionPayload { code: status.code });
-          break;
-        }
-        _ => {}
-      }
-    }
+    let rx = ctx.spawn_ffmpeg(args)?;
+    wait_ffmpeg(rx, &temp_name, |stats| {
+      // 发射进度事件到前端
+      ctx.emit_progress(ProgressPayload::new(
+        ctx,
+        stats,
+        duration,
+        video_info.clone(),
+        format!("split segment {}: {}", seg.start, seg.duration),
+      ));
+    })
+    .await?;
+
+    temp_files.push(temp_name);
+    let _ = app.emit("ffmpeg-complete", CompletionPayload { job_id: ctx.id, code: Some(0) });
   }
 
   if temp_files.is_empty() {
-    return Err("not cut video".to_string());
+    return Err("not cut video".to_string().into());
   }
 
   // 2. 创建 concat 列表文件
@@ -472,38 +580,25 @@ pub async fn create_highlight_video(
   }
   list_file.flush().map_err(|e| e.to_string())?;
 
-  let shell = app.shell();
   let file_path = list_file_name.to_string_lossy().into_owned();
   let args =
     Vec::from(["-f", "concat", "-safe", "0", "-i", &file_path, "-c", "copy", "-y", output_path, "-hide_banner"]);
-  let (mut rx, _child) = shell
-    .sidecar("ffmpeg")
-    .map_err(|e| format!("Failed to create sidecar: {}", e))?
-    .args(args)
-    .spawn()
-    .map_err(|e| e.to_string())?;
+  let rx = ctx.spawn_ffmpeg(args)?;
+  wait_ffmpeg(rx, output_path, |_| {}).await?;
 
-  while let Some(event) = rx.recv().await {
-    match event {
-      CommandEvent::Terminated(status) => {
-        let _ = std::fs::remove_file(list_file_name);
-        let _ = std::fs::remove_dir_all(temp_dir); // 删除临时文件夹
+  let _ = std::fs::remove_file(list_file_name);
+  let _ = std::fs::remove_dir_all(temp_dir); // 删除临时文件夹
 
-        let _ = app.emit("ffmpeg-complete", CompletionPayload { code: status.code });
-        break;
-      }
-      _ => {}
-    }
-  }
+  let _ = app.emit("ffmpeg-complete", CompletionPayload { job_id: ctx.id, code: Some(0) });
 
   Ok(())
 }
 
 /// 智能合并
-#[tauri::command]
-pub async fn merge_smart(app: AppHandle, inputs: Vec<&str>, output_path: &str) -> Result<(), String> {
+async fn run_merge(ctx: &FfmpegJobContext, inputs: Vec<&str>, output_path: &str) -> Result<(), FfmpegError> {
+  let app = ctx.app.clone();
   if inputs.is_empty() {
-    return Err("not find video".to_string());
+    return Err("not find video".to_string().into());
   }
 
   // 关键步骤：构建一个包含 (路径, 元数据) 的有效列表
@@ -511,7 +606,7 @@ pub async fn merge_smart(app: AppHandle, inputs: Vec<&str>, output_path: &str) -
   let mut valid_tasks: Vec<(&str, VideoInfo)> = Vec::new();
 
   for input in &inputs {
-    match get_video_info(app.clone(), &input).await {
+    match probe_media(&app, input).await {
       Ok(meta) => {
         valid_tasks.push((input, meta));
       }
@@ -520,7 +615,7 @@ pub async fn merge_smart(app: AppHandle, inputs: Vec<&str>, output_path: &str) -
   }
 
   if valid_tasks.is_empty() {
-    return Err("no valid video".to_string());
+    return Err("no valid video".to_string().into());
   }
 
   // 提取纯 meta 列表用于计算
@@ -530,8 +625,7 @@ pub async fn merge_smart(app: AppHandle, inputs: Vec<&str>, output_path: &str) -
 
   log::info!(" target resolute: {}x{} | fps: {}", target_w, target_h, target_fps);
 
-  let gpus = get_gpu_info().await.unwrap();
-  let best = select_best_encoder(&gpus);
+  let best = best_encoder().await;
   let mut best_args = best.to_ffmpeg_args();
 
   best_args.pop();
@@ -541,22 +635,21 @@ pub async fn merge_smart(app: AppHandle, inputs: Vec<&str>, output_path: &str) -
   // 构建 Filter Complex
   let mut filter_complex = String::new();
   // 2. 创建 concat 列表文件
-  let cache_dir = get_cache_dir(app.clone())?;
-  let temp_dir = cache_dir.join("temp");
-  let _ = std::fs::create_dir(&temp_dir);
+  let temp_dir = ctx.temp_dir()?;
   let filter_file_name = temp_dir.join("filter.txt");
   let mut filter_file = File::create(&filter_file_name).map_err(|e| e.to_string())?;
 
   let font_path = get_default_font_path();
 
-  let mut args = Vec::with_capacity(8 + best_args.len() + inputs.len() * 2);
+  let mut args = Vec::with_capacity(8 + best_args.len() + valid_tasks.len() * 2);
 
-  for (i, input_path) in inputs.iter().enumerate() {
+  // 只合并能读取到信息的视频, 与后面 concat 的数量保持一致
+  for (i, (input_path, _)) in valid_tasks.iter().enumerate() {
     args.push("-i");
     args.push(input_path);
 
     // 获取文件名
-    let filename = Path::new(input_path).file_name().unwrap().to_string_lossy();
+    let filename = file_name_of(input_path);
     // 清洗文件名，防止破坏 FFmpeg 语法
     let clean_name = filename.replace(":", "\\:").replace("'", "");
     let target_w_f: f64 = target_w as f64;
@@ -612,53 +705,34 @@ pub async fn merge_smart(app: AppHandle, inputs: Vec<&str>, output_path: &str) -
 
   log::info!("ffmpeg {}", args.join(" "));
 
-  let shell = app.shell();
-  let (mut rx, _child) = shell
-    .sidecar("ffmpeg")
-    .map_err(|e| format!("Failed to create sidecar: {}", e))?
-    .args(args)
-    .spawn()
-    .map_err(|e| e.to_string())?;
-
-  while let Some(event) = rx.recv().await {
-    match event {
-      CommandEvent::Stderr(line) => {
-        // log::info!("{}", String::from_utf8_lossy(&line));
-        if let Some(current_time) = parse_time_from_ffmpeg_output(&line) {
-          let progress = if target_duration > 0.0 { (current_time / target_duration) * 100.0 } else { 0.0 };
-
-          let mut dur = 0.0;
-          let mut video_info_target = valid_tasks.get(0).unwrap().1.clone();
-          let mut video_name = Path::new(&video_info_target.path).file_name().unwrap().to_string_lossy().into_owned();
-
-          for (_, video_info) in &valid_tasks {
-            dur += video_info.duration;
-            if current_time < dur {
-              video_info_target = video_info.clone();
-              video_name = Path::new(&video_info_target.path).file_name().unwrap().to_string_lossy().into_owned();
-              break;
-            }
-          }
-
-          // 发射进度事件到前端
-          let _ = app.emit(
-            "ffmpeg-progress",
-            ProgressPayload {
-              progress,
-              video_info: video_info_target,
-              message: format!("concat video: {}", video_name),
-            },
-          );
-        }
-      }
-      CommandEvent::Terminated(status) => {
-        let _ = app.emit("ffmpeg-complete", CompletionPayload { code: status.code });
-        let _ = std::fs::remove_dir_all(temp_dir);
-        break;
-      }
-      _ => {}
+  let rx = ctx.spawn_ffmpeg(args)?;
+
+  wait_ffmpeg(rx, output_path, |stats| {
+    // 找到当前正在处理的视频, 超出总时长时取最后一个
+    let mut dur = 0.0;
+    let current = valid_tasks
+      .iter()
+      .find(|(_, video_info)| {
+        dur += video_info.duration;
+        stats.out_time < dur
+      })
+      .or(valid_tasks.last());
+
+    if let Some((input_path, video_info)) = current {
+      // 发射进度事件到前端
+      ctx.emit_progress(ProgressPayload::new(
+        ctx,
+        stats,
+        target_duration,
+        video_info.clone(),
+        format!("concat video: {}", file_name_of(input_path)),
+      ));
     }
-  }
+  })
+  .await?;
+
+  let _ = app.emit("ffmpeg-complete", CompletionPayload { job_id: ctx.id, code: Some(0) });
+  let _ = std::fs::remove_dir_all(temp_dir);
 
   Ok(())
 }
@@ -668,19 +742,19 @@ pub async fn merge_smart(app: AppHandle, inputs: Vec<&str>, output_path: &str) -
 /// 1. Base Video -> Remux to .ts (不重编码，超快)
 /// 2. New Videos -> Transcode to .ts (统一参数)
 /// 3. Concat all .ts files -> Remux to .mp4
-#[tauri::command]
-pub async fn append_smart(
-  app: AppHandle,
+async fn run_append(
+  ctx: &FfmpegJobContext,
   base_path: &str,
   new_inputs: Vec<&str>,
   output_path: &str,
-) -> Result<(), String> {
+) -> Result<(), FfmpegError> {
+  let app = ctx.app.clone();
   if new_inputs.is_empty() {
-    return Err("no new videos to append".to_string());
+    return Err("no new videos to append".to_string().into());
   }
 
-  let base_info = get_video_info(app.clone(), base_path).await?;
-  let temp_dir = get_cache_temp_dir(app.clone())?;
+  let base_info = probe_media(&app, base_path).await?;
+  let temp_dir = ctx.temp_dir()?;
   let mut ts_files: Vec<String> = Vec::new();
 
   // ==========================================
@@ -711,39 +785,25 @@ pub async fn append_smart(
   ];
 
   log::info!("Remuxing base to TS...");
-  let shell = app.shell();
-  let (mut rx, _) = shell
-    .sidecar("ffmpeg")
-    .map_err(|e| format!("Failed to create sidecar: {}", e))?
-    .args(remux_args)
-    .spawn()
-    .map_err(|e| e.to_string())?;
+  let rx = ctx.spawn_ffmpeg(remux_args)?;
 
   // 等待基准视频处理完成
-  while let Some(event) = rx.recv().await {
-    if let CommandEvent::Terminated(status) = event {
-      if status.code != Some(0) {
-        return Err("Failed to remux base video".to_string());
-      }
-      break;
-    }
-  }
+  wait_ffmpeg(rx, &base_ts_path, |_| {}).await?;
   ts_files.push(base_ts_path);
 
   // ==========================================
   // 步骤 2: 处理新视频并转码为 TS
   // ==========================================
-  let gpus = get_gpu_info().await.unwrap();
-  let best = select_best_encoder(&gpus);
+  let best = best_encoder().await;
   let best_args = best.to_ffmpeg_args();
   let font_path = get_default_font_path();
 
   for (i, input_path) in new_inputs.iter().enumerate() {
     let current_ts_path = temp_dir.join(format!("part_new_{}.ts", i)).to_string_lossy().into_owned();
-    let input_info = get_video_info(app.clone(), input_path).await.unwrap_or_default();
+    let input_info = probe_media(&app, input_path).await?;
 
     // 画面处理滤镜 (同之前逻辑)
-    let filename = Path::new(input_path).file_name().unwrap().to_string_lossy();
+    let filename = file_name_of(input_path);
     let clean_name = filename.replace(":", "\\:").replace("'", "");
     let target_w_f = base_info.width as f64;
     let target_h_f = base_info.height as f64;
@@ -777,38 +837,19 @@ pub async fn append_smart(
     args.push("-hide_banner");
 
     log::info!("Transcoding part {} to TS...", i);
-    let shell = app.shell();
-    let (mut rx, _) = shell
-      .sidecar("ffmpeg")
-      .map_err(|e| format!("Failed to create sidecar: {}", e))?
-      .args(args)
-      .spawn()
-      .map_err(|e| e.to_string())?;
-
-    while let Some(event) = rx.recv().await {
-      match event {
-        CommandEvent::Stderr(line) => {
-          if let Some(current_time) = parse_time_from_ffmpeg_output(&line) {
-            let progress = if input_info.duration > 0.0 { (current_time / input_info.duration) * 100.0 } else { 0.0 };
-            let _ = app.emit(
-              "ffmpeg-progress",
-              ProgressPayload {
-                progress,
-                video_info: input_info.clone(),
-                message: format!("Processing part {}/{}", i + 1, new_inputs.len()),
-              },
-            );
-          }
-        }
-        CommandEvent::Terminated(status) => {
-          if status.code == Some(0) {
-            ts_files.push(current_ts_path.clone());
-          }
-          break;
-        }
-        _ => {}
-      }
-    }
+    let rx = ctx.spawn_ffmpeg(args)?;
+
+    wait_ffmpeg(rx, &current_ts_path, |stats| {
+      ctx.emit_progress(ProgressPayload::new(
+        ctx,
+        stats,
+        input_info.duration,
+        input_info.clone(),
+        format!("Processing part {}/{}", i + 1, new_inputs.len()),
+      ));
+    })
+    .await?;
+    ts_files.push(current_ts_path);
   }
 
   // ==========================================
@@ -847,27 +888,88 @@ pub async fn append_smart(
   ];
 
   log::info!("Final merge (TS -> MP4)...");
-  let shell = app.shell();
-  let (mut rx, _) = shell
-    .sidecar("ffmpeg")
-    .map_err(|e| format!("Failed to create sidecar: {}", e))?
-    .args(concat_args)
-    .spawn()
-    .map_err(|e: tauri_plugin_shell::Error| e.to_string())?;
+  let rx = ctx.spawn_ffmpeg(concat_args)?;
+  wait_ffmpeg(rx, output_path, |_| {}).await?;
 
-  while let Some(event) = rx.recv().await {
-    if let CommandEvent::Terminated(status) = event {
-      // 清理
-      let _ = std::fs::remove_file(list_file_name);
-      for tmp in ts_files {
-        let _ = std::fs::remove_file(tmp);
-      }
-      let _ = std::fs::remove_dir(temp_dir);
+  // 清理
+  let _ = std::fs::remove_file(list_file_name);
+  for tmp in ts_files {
+    let _ = std::fs::remove_file(tmp);
+  }
+  let _ = std::fs::remove_dir(temp_dir);
 
-      let _ = app.emit("ffmpeg-complete", CompletionPayload { code: status.code });
-      break;
+  let _ = app.emit("ffmpeg-complete", CompletionPayload { job_id: ctx.id, code: Some(0) });
+  Ok(())
+}
+
+/// 执行队列中的任务 <br>
+/// ffmpeg 先写到 partial_output_path, 成功后再重命名为输出路径, 失败或取消时不会留下不完整的输出文件
+pub(crate) async fn run_ffmpeg_job(ctx: &FfmpegJobContext, kind: &FfmpegJobKind) -> Result<(), FfmpegError> {
+  let partial = kind.partial_output_path(ctx.id);
+  let partial_path = partial.to_string_lossy();
+
+  let result = match kind {
+    FfmpegJobKind::Convert { video_path, .. } => run_convert(ctx, video_path, &partial_path).await,
+    FfmpegJobKind::Highlight { video_path, segments, .. } => {
+      run_highlight(ctx, video_path, &partial_path, segments).await
+    }
+    FfmpegJobKind::Merge { inputs, .. } => {
+      run_merge(ctx, inputs.iter().map(String::as_str).collect(), &partial_path).await
+    }
+    FfmpegJobKind::Append { base_path, new_inputs, .. } => {
+      run_append(ctx, base_path, new_inputs.iter().map(String::as_str).collect(), &partial_path).await
     }
+  };
+
+  let output_path = kind.output_path();
+  let result = result.and_then(|()| {
+    // 最后一步 ffmpeg 响应 q 正常退出时, 任务也会返回成功
+    if ctx.is_cancelled() {
+      return Err(FfmpegError::Cancelled);
+    }
+    std::fs::rename(&partial, output_path)
+      .map_err(|e| FfmpegError::Other { message: format!("failed to move output to {}: {}", output_path, e) })
+  });
+
+  if result.is_err() {
+    let _ = std::fs::remove_file(&partial);
   }
+  result.map_err(|e| match e {
+    FfmpegError::DiskFull { .. } => FfmpegError::DiskFull { path: output_path.to_string() },
+    e => e,
+  })
+}
 
-  Ok(())
+/// 将视频转换成 mp4 格式 (加入任务队列并等待完成)
+#[tauri::command]
+pub async fn convert_video_to_mp4(app: AppHandle, video_path: String, output_path: String) -> Result<(), FfmpegError> {
+  enqueue_and_wait(&app, FfmpegJobKind::Convert { video_path, output_path }).await
+}
+
+/// 裁剪和合并视频，来截取精彩的片段 (加入任务队列并等待完成)
+#[tauri::command]
+pub async fn create_highlight_video(
+  app: AppHandle,
+  video_path: String,
+  output_path: String,
+  segments: Vec<TimeSegment>,
+) -> Result<(), FfmpegError> {
+  enqueue_and_wait(&app, FfmpegJobKind::Highlight { video_path, output_path, segments }).await
+}
+
+/// 智能合并 (加入任务队列并等待完成)
+#[tauri::command]
+pub async fn merge_smart(app: AppHandle, inputs: Vec<String>, output_path: String) -> Result<(), FfmpegError> {
+  enqueue_and_wait(&app, FfmpegJobKind::Merge { inputs, output_path }).await
+}
+
+/// 智能追加视频 (加入任务队列并等待完成)
+#[tauri::command]
+pub async fn append_smart(
+  app: AppHandle,
+  base_path: String,
+  new_inputs: Vec<String>,
+  output_path: String,
+) -> Result<(), FfmpegError> {
+  enqueue_and_wait(&app, FfmpegJobKind::Append { base_path, new_inputs, output_path }).await
 }
@@ -0,0 +1,232 @@
+// 通过 ffprobe sidecar 读取媒体信息: 输出 JSON, 反序列化后转换为前端使用的 VideoInfo
+use std::{collections::HashMap, path::Path};
+
+use serde::Deserialize;
+use tauri::AppHandle;
+use tauri_plugin_shell::ShellExt;
+
+use crate::shell::ffmpeg::{FfmpegError, MediaChapter, MediaStream, MediaStreamKind, VideoInfo};
+
+/// ffprobe 的 JSON 输出, 只声明用到的字段 <br>
+/// ffprobe 把时长、码率、采样率等数值输出为字符串, 这里先按字符串接收再解析
+#[derive(Deserialize)]
+struct ProbeOutput {
+  #[serde(default)]
+  streams: Vec<ProbeStream>,
+  #[serde(default)]
+  chapters: Vec<ProbeChapter>,
+  format: Option<ProbeFormat>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct ProbeStream {
+  index: u32,
+  codec_type: MediaStreamKind,
+  codec_name: Option<String>,
+  codec_long_name: Option<String>,
+  profile: Option<String>,
+  width: Option<u32>,
+  height: Option<u32>,
+  pix_fmt: Option<String>,
+  color_range: Option<String>,
+  color_space: Option<String>,
+  color_transfer: Option<String>,
+  color_primaries: Option<String>,
+  r_frame_rate: Option<String>,
+  avg_frame_rate: Option<String>,
+  sample_rate: Option<String>,
+  channels: Option<u32>,
+  channel_layout: Option<String>,
+  bit_rate: Option<String>,
+  duration: Option<String>,
+  disposition: HashMap<String, i64>,
+  tags: HashMap<String, String>,
+  side_data_list: Vec<ProbeSideData>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct ProbeSideData {
+  /// Display Matrix 中的旋转角度, 逆时针为正
+  rotation: Option<f64>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct ProbeChapter {
+  id: i64,
+  start_time: Option<String>,
+  end_time: Option<String>,
+  tags: HashMap<String, String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct ProbeFormat {
+  format_name: String,
+  format_long_name: String,
+  duration: Option<String>,
+  size: Option<String>,
+  bit_rate: Option<String>,
+  tags: HashMap<String, String>,
+}
+
+impl ProbeStream {
+  /// 显示时需要顺时针旋转的角度 (0/90/180/270) <br>
+  /// 新版 ffprobe 写在 side data 的 Display Matrix 中, 旧版写在 rotate 标签中
+  fn rotation(&self) -> u32 {
+    let degrees = self
+      .side_data_list
+      .iter()
+      .find_map(|data| data.rotation)
+      .map(|rotation| -rotation)
+      .or_else(|| self.tags.get("rotate").and_then(|rotate| rotate.parse().ok()))
+      .unwrap_or(0.0);
+
+    ((degrees / 90.0).round() as i64 * 90).rem_euclid(360) as u32
+  }
+
+  fn into_media_stream(self) -> MediaStream {
+    let rotation = self.rotation();
+    let fps =
+      self.avg_frame_rate.as_deref().and_then(parse_rate).or_else(|| self.r_frame_rate.as_deref().and_then(parse_rate));
+
+    MediaStream {
+      index: self.index,
+      kind: self.codec_type,
+      codec_name: self.codec_name.unwrap_or_default(),
+      codec_long_name: self.codec_long_name.unwrap_or_default(),
+      profile: self.profile,
+      bitrate_kbps: self.bit_rate.as_deref().and_then(parse_kbps),
+      duration: self.duration.as_deref().and_then(parse_seconds),
+      language: self.tags.get("language").cloned(),
+      title: self.tags.get("title").cloned(),
+      is_default: self.disposition.get("default").is_some_and(|v| *v != 0),
+      is_attached_pic: self.disposition.get("attached_pic").is_some_and(|v| *v != 0),
+      width: self.width,
+      height: self.height,
+      fps,
+      rotation,
+      pix_fmt: self.pix_fmt,
+      color_range: self.color_range,
+      color_space: self.color_space,
+      color_transfer: self.color_transfer,
+      color_primaries: self.color_primaries,
+      sample_rate: self.sample_rate.and_then(|rate| rate.parse().ok()),
+      channels: self.channels,
+      channel_layout: self.channel_layout,
+      file_name: self.tags.get("filename").cloned(),
+      mime_type: self.tags.get("mimetype").cloned(),
+      tags: self.tags,
+    }
+  }
+}
+
+impl ProbeOutput {
+  fn into_video_info(self, path: &str) -> VideoInfo {
+    let format = self.format.unwrap_or_default();
+    let streams: Vec<MediaStream> = self.streams.into_iter().map(ProbeStream::into_media_stream).collect();
+    let chapters = self
+      .chapters
+      .into_iter()
+      .map(|chapter| MediaChapter {
+        id: chapter.id,
+        start: chapter.start_time.as_deref().and_then(parse_seconds).unwrap_or(0.0),
+        end: chapter.end_time.as_deref().and_then(parse_seconds).unwrap_or(0.0),
+        title: chapter.tags.get("title").cloned(),
+        tags: chapter.tags,
+      })
+      .collect();
+
+    // 封面图片也是视频流, 不作为主视频流
+    let video = streams.iter().find(|s| s.kind == MediaStreamKind::Video && !s.is_attached_pic);
+    let audio = streams.iter().find(|s| s.kind == MediaStreamKind::Audio);
+
+    // 容器没有时长时 (如部分 mkv、裸流), 取最长的流
+    let duration = format
+      .duration
+      .as_deref()
+      .and_then(parse_seconds)
+      .or_else(|| streams.iter().filter_map(|s| s.duration).reduce(f64::max))
+      .unwrap_or(0.0);
+
+    // 旋转 90/270 度的视频按显示方向返回宽高
+    let (width, height) = match video {
+      Some(v) if v.rotation % 180 == 90 => (v.height.unwrap_or(0), v.width.unwrap_or(0)),
+      Some(v) => (v.width.unwrap_or(0), v.height.unwrap_or(0)),
+      None => (0, 0),
+    };
+
+    VideoInfo {
+      path: path.to_string(),
+      width,
+      height,
+      fps: video.and_then(|v| v.fps).unwrap_or(0.0),
+      duration,
+      video_codec: video.map(|v| v.codec_name.clone()).unwrap_or_default(),
+      audio_codec: audio.map(|a| a.codec_name.clone()).unwrap_or_default(),
+      audio_sample_rate: audio.and_then(|a| a.sample_rate).unwrap_or(0),
+      bitrate_kbps: format.bit_rate.as_deref().and_then(parse_kbps).unwrap_or(0),
+      rotation: video.map(|v| v.rotation).unwrap_or(0),
+      pix_fmt: video.and_then(|v| v.pix_fmt.clone()),
+      format_name: format.format_name,
+      format_long_name: format.format_long_name,
+      size: format.size.and_then(|size| size.parse().ok()).unwrap_or(0),
+      tags: format.tags,
+      streams,
+      chapters,
+    }
+  }
+}
+
+/// 解析 "30000/1001" 格式的帧率, 分子或分母为 0 时返回 None
+fn parse_rate(rate: &str) -> Option<f64> {
+  let (num, den) = rate.split_once('/')?;
+  let (num, den): (f64, f64) = (num.parse().ok()?, den.parse().ok()?);
+  (num > 0.0 && den > 0.0).then(|| num / den)
+}
+
+/// 解析秒数, ffprobe 对未知值输出 "N/A"
+fn parse_seconds(value: &str) -> Option<f64> {
+  value.parse().ok().filter(|v: &f64| v.is_finite() && *v >= 0.0)
+}
+
+/// bit/s 转换为 kb/s
+fn parse_kbps(value: &str) -> Option<u32> {
+  value.parse::<u64>().ok().map(|bps| (bps / 1000) as u32)
+}
+
+/// 调用 ffprobe 读取媒体信息, 支持纯音频文件
+pub async fn probe_media(app: &AppHandle, path: &str) -> Result<VideoInfo, FfmpegError> {
+  if !Path::new(path).exists() {
+    return Err(FfmpegError::InputMissing { path: path.to_string() });
+  }
+  let probe_failed = |message: String| FfmpegError::ProbeFailed { path: path.to_string(), message };
+
+  let output = app
+    .shell()
+    .sidecar("ffprobe")
+    .map_err(|e| format!("Failed to create sidecar: {}", e))?
+    .args(["-v", "error", "-print_format", "json", "-show_streams", "-show_format", "-show_chapters", "-i", path])
+    .output()
+    .await
+    .map_err(|e| probe_failed(e.to_string()))?;
+
+  if !output.status.success() {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    return Err(probe_failed(format!(
+      "ffprobe exited with status {:?}: {}",
+      output.status.code(),
+      stderr.trim()
+    )));
+  }
+
+  let probe: ProbeOutput = serde_json::from_slice(&output.stdout)
+    .map_err(|e| probe_failed(format!("Failed to parse ffprobe output: {}", e)))?;
+  if probe.streams.is_empty() {
+    return Err(probe_failed("no media streams found".to_string()));
+  }
+
+  Ok(probe.into_video_info(path))
+}
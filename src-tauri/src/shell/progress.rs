@@ -0,0 +1,63 @@
+// 解析 ffmpeg -progress pipe:1 输出的进度: 每行一个 key=value, 以 progress=continue/end 结束一个进度块
+use serde::{Deserialize, Serialize};
+
+use crate::shell::ffmpeg::parse_duration_str;
+
+/// 传给 ffmpeg 的进度参数, 进度写到 stdout, 并关闭 stderr 上的统计行
+pub const PROGRESS_ARGS: [&str; 3] = ["-progress", "pipe:1", "-nostats"];
+
+/// 一个进度块, ffmpeg 输出 N/A 的字段为 None
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FfmpegProgress {
+  pub frame: u64,
+  pub fps: Option<f64>,
+  pub bitrate_kbps: Option<f64>,
+  /// 已输出的时长 (秒)
+  pub out_time: f64,
+  /// 编码速度, 1.0 表示与实时播放相同
+  pub speed: Option<f64>,
+  /// 已输出的字节数
+  pub total_size: Option<u64>,
+  /// 最后一个进度块 (progress=end)
+  pub finished: bool,
+}
+
+/// 逐行累积进度字段, 一个进度块结束时返回完整的 FfmpegProgress
+#[derive(Default)]
+pub struct ProgressParser {
+  current: FfmpegProgress,
+}
+
+impl ProgressParser {
+  /// 传入 stdout 的一行 (sidecar 已去掉换行符)
+  pub fn feed_line(&mut self, line: &[u8]) -> Option<FfmpegProgress> {
+    let line = String::from_utf8_lossy(line);
+    let (key, value) = line.trim().split_once('=')?;
+    let value = value.trim();
+
+    match key {
+      "frame" => self.current.frame = value.parse().unwrap_or(self.current.frame),
+      "fps" => self.current.fps = value.parse().ok(),
+      "bitrate" => self.current.bitrate_kbps = value.trim_end_matches("kbits/s").parse().ok(),
+      "total_size" => self.current.total_size = value.parse().ok(),
+      // out_time_ms 实际上也是微秒, 旧版 ffmpeg 只输出这个字段
+      "out_time_us" | "out_time_ms" => {
+        if let Ok(us) = value.parse::<i64>() {
+          self.current.out_time = us.max(0) as f64 / 1_000_000.0;
+        }
+      }
+      "out_time" => {
+        if let Some(seconds) = parse_duration_str(value) {
+          self.current.out_time = seconds;
+        }
+      }
+      "speed" => self.current.speed = value.trim_end_matches('x').trim().parse().ok(),
+      "progress" => {
+        self.current.finished = value == "end";
+        return Some(self.current.clone());
+      }
+      _ => {}
+    }
+    None
+  }
+}
@@ -0,0 +1,609 @@
+// ffmpeg 任务队列: 所有 ffmpeg 任务先进入队列, 由调度器按顺序执行, 同时运行的任务数不超过 max_concurrency
+// 队列保存在缓存目录中, 应用重启后未完成的任务会重新执行
+use std::{
+  collections::{HashMap, HashSet},
+  ffi::OsStr,
+  path::{Path, PathBuf},
+  sync::Mutex,
+  time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tauri::{
+  async_runtime::{self, JoinHandle, Receiver},
+  AppHandle, Emitter, Manager, State,
+};
+use tauri_plugin_shell::{
+  process::{CommandChild, CommandEvent},
+  ShellExt,
+};
+use tokio::sync::{oneshot, Notify};
+use tokio_util::sync::CancellationToken;
+
+use crate::shell::ffmpeg::{run_ffmpeg_job, FfmpegError, ProgressPayload, TimeSegment};
+use crate::shell::progress::PROGRESS_ARGS;
+use crate::utils::{
+  files::{get_cache_dir, get_cache_temp_dir},
+  stats::now_millis,
+};
+
+/// 队列文件名, 保存在缓存目录中
+const QUEUE_FILE_NAME: &str = "ffmpeg_queue.json";
+/// 取消任务时先向 ffmpeg 发送 q 让它自行退出, 超过该时间仍未退出则强制结束
+const CANCEL_GRACE_PERIOD: Duration = Duration::from_secs(3);
+/// 保留的已结束任务数量, 超出时删除最早结束的任务, 避免任务列表和队列文件无限增长
+const MAX_FINISHED_JOBS: usize = 50;
+
+/// 任务类型及参数
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase", rename_all_fields = "camelCase")]
+pub enum FfmpegJobKind {
+  /// 转换为 mp4
+  Convert { video_path: String, output_path: String },
+  /// 截取精彩片段
+  Highlight { video_path: String, output_path: String, segments: Vec<TimeSegment> },
+  /// 智能合并
+  Merge { inputs: Vec<String>, output_path: String },
+  /// 智能追加
+  Append { base_path: String, new_inputs: Vec<String>, output_path: String },
+}
+
+impl FfmpegJobKind {
+  pub fn output_path(&self) -> &str {
+    match self {
+      Self::Convert { output_path, .. }
+      | Self::Highlight { output_path, .. }
+      | Self::Merge { output_path, .. }
+      | Self::Append { output_path, .. } => output_path,
+    }
+  }
+
+  /// 任务运行时 ffmpeg 实际写入的文件, 与输出文件在同一目录, 保留扩展名以便 ffmpeg 识别输出格式 <br>
+  /// 任务成功后重命名为 output_path, 失败或取消时只删除这个文件, 不会影响已存在的同名文件
+  pub fn partial_output_path(&self, job_id: u64) -> PathBuf {
+    let output = Path::new(self.output_path());
+    let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match output.extension() {
+      Some(ext) => format!("{}.partial-{}.{}", stem, job_id, ext.to_string_lossy()),
+      None => format!("{}.partial-{}", stem, job_id),
+    };
+    output.with_file_name(name)
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum FfmpegJobStatus {
+  Pending,
+  Running,
+  Completed,
+  Failed,
+  Cancelled,
+}
+
+impl FfmpegJobStatus {
+  /// 已完成、失败或取消
+  pub fn is_finished(self) -> bool {
+    matches!(self, Self::Completed | Self::Failed | Self::Cancelled)
+  }
+}
+
+/// 队列中的一个任务, 时间均为 Unix 毫秒
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FfmpegJob {
+  pub id: u64,
+  pub kind: FfmpegJobKind,
+  pub status: FfmpegJobStatus,
+  /// 进度百分比 (0-100)
+  pub progress: f64,
+  pub error: Option<FfmpegError>,
+  pub created_at: u64,
+  pub started_at: Option<u64>,
+  pub finished_at: Option<u64>,
+}
+
+/// 持久化的队列状态
+#[derive(Serialize, Deserialize)]
+struct QueueData {
+  next_id: u64,
+  max_concurrency: usize,
+  paused: bool,
+  jobs: Vec<FfmpegJob>,
+}
+
+impl Default for QueueData {
+  fn default() -> Self {
+    // ffmpeg 本身会占满 CPU/GPU, 默认一次只运行一个任务
+    Self { next_id: 0, max_concurrency: 1, paused: false, jobs: Vec::new() }
+  }
+}
+
+impl QueueData {
+  /// 把等待中的任务移动到等待中任务的第 index 个位置, 已结束和运行中的任务不参与计数 <br>
+  /// index 超出范围时移动到最后一个等待中的任务之后
+  fn reorder(&mut self, job_id: u64, index: usize) -> Result<(), String> {
+    let from = self.jobs.iter().position(|job| job.id == job_id).ok_or("job not found")?;
+    if self.jobs[from].status != FfmpegJobStatus::Pending {
+      return Err("only pending jobs can be reordered".to_string());
+    }
+
+    let job = self.jobs.remove(from);
+    let pending: Vec<usize> =
+      self.jobs.iter().enumerate().filter(|(_, job)| job.status == FfmpegJobStatus::Pending).map(|(i, _)| i).collect();
+    let to = match pending.get(index) {
+      Some(&to) => to,
+      None => pending.last().map_or(from, |&last| last + 1),
+    };
+    self.jobs.insert(to, job);
+    Ok(())
+  }
+
+  /// 只保留最近结束的 MAX_FINISHED_JOBS 个任务
+  fn prune_finished(&mut self) {
+    let mut finished: Vec<(u64, u64)> = self
+      .jobs
+      .iter()
+      .filter(|job| job.status.is_finished())
+      .map(|job| (job.finished_at.unwrap_or_default(), job.id))
+      .collect();
+    if finished.len() <= MAX_FINISHED_JOBS {
+      return;
+    }
+
+    finished.sort_unstable();
+    let expired: HashSet<u64> = finished[..finished.len() - MAX_FINISHED_JOBS].iter().map(|&(_, id)| id).collect();
+    self.jobs.retain(|job| !expired.contains(&job.id));
+  }
+}
+
+/// ffmpeg 任务队列
+pub struct FfmpegQueue {
+  data: Mutex<QueueData>,
+  /// 队列文件路径, 获取缓存目录失败时不持久化
+  path: Option<PathBuf>,
+  /// 队列变化 (新任务、任务结束、恢复等) 时唤醒调度器
+  notify: Notify,
+  /// 等待任务结束的调用方, 兼容原有的同步命令
+  waiters: Mutex<HashMap<u64, oneshot::Sender<Result<(), FfmpegError>>>>,
+  /// 运行中任务的取消令牌
+  cancel_tokens: Mutex<HashMap<u64, CancellationToken>>,
+  /// 运行中任务当前的 ffmpeg 进程
+  children: Mutex<HashMap<u64, CommandChild>>,
+}
+
+impl FfmpegQueue {
+  /// 从缓存目录加载队列 <br>
+  /// 上次运行中断的任务重新排队, 已结束的任务不再保留
+  pub fn load(app: &AppHandle) -> Self {
+    let path = get_cache_dir(app.clone()).ok().map(|dir| dir.join(QUEUE_FILE_NAME));
+
+    let mut data: QueueData = path
+      .as_ref()
+      .and_then(|path| std::fs::read(path).ok())
+      .and_then(|content| serde_json::from_slice(&content).ok())
+      .unwrap_or_default();
+    data.jobs.retain(|job| matches!(job.status, FfmpegJobStatus::Pending | FfmpegJobStatus::Running));
+    for job in &mut data.jobs {
+      job.status = FfmpegJobStatus::Pending;
+      job.progress = 0.0;
+      job.started_at = None;
+    }
+
+    let queue = Self {
+      data: Mutex::new(data),
+      path,
+      notify: Notify::new(),
+      waiters: Default::default(),
+      cancel_tokens: Default::default(),
+      children: Default::default(),
+    };
+    queue.save(&queue.data.lock().unwrap());
+    queue
+  }
+
+  fn save(&self, data: &QueueData) {
+    let Some(path) = &self.path else {
+      return;
+    };
+    match serde_json::to_vec_pretty(data) {
+      Ok(content) => {
+        if let Err(e) = std::fs::write(path, content) {
+          log::error!("Failed to save ffmpeg queue: {}", e);
+        }
+      }
+      Err(e) => log::error!("Failed to serialize ffmpeg queue: {}", e),
+    }
+  }
+
+  /// 修改队列并保存, 之后唤醒调度器
+  fn update<T>(&self, f: impl FnOnce(&mut QueueData) -> T) -> T {
+    let mut data = self.data.lock().unwrap();
+    let result = f(&mut data);
+    self.save(&data);
+    self.notify.notify_one();
+    result
+  }
+
+  pub fn enqueue(&self, kind: FfmpegJobKind) -> u64 {
+    self.update(|data| {
+      data.next_id += 1;
+      let id = data.next_id;
+      data.jobs.push(FfmpegJob {
+        id,
+        kind,
+        status: FfmpegJobStatus::Pending,
+        progress: 0.0,
+        error: None,
+        created_at: now_millis(),
+        started_at: None,
+        finished_at: None,
+      });
+      id
+    })
+  }
+
+  pub fn jobs(&self) -> Vec<FfmpegJob> {
+    self.data.lock().unwrap().jobs.clone()
+  }
+
+  /// 取出下一个可以运行的任务并标记为运行中, 队列暂停或达到并发上限时返回 None
+  fn next_runnable(&self) -> Option<FfmpegJob> {
+    let mut data = self.data.lock().unwrap();
+    let running = data.jobs.iter().filter(|job| job.status == FfmpegJobStatus::Running).count();
+    if data.paused || running >= data.max_concurrency {
+      return None;
+    }
+
+    let job = data.jobs.iter_mut().find(|job| job.status == FfmpegJobStatus::Pending)?;
+    job.status = FfmpegJobStatus::Running;
+    job.started_at = Some(now_millis());
+    let job = job.clone();
+
+    self.save(&data);
+    Some(job)
+  }
+
+  /// 更新运行中任务的进度, 只保存在内存中
+  pub fn set_progress(&self, job_id: u64, progress: f64) {
+    if let Some(job) = self.data.lock().unwrap().jobs.iter_mut().find(|job| job.id == job_id) {
+      job.progress = progress.clamp(0.0, 100.0);
+    }
+  }
+
+  /// 标记任务结束, status 为 Completed、Failed 或 Cancelled
+  fn finish(&self, job_id: u64, status: FfmpegJobStatus, error: Option<FfmpegError>) -> Option<FfmpegJob> {
+    self.cancel_tokens.lock().unwrap().remove(&job_id);
+    self.children.lock().unwrap().remove(&job_id);
+
+    let result = match (status, &error) {
+      (FfmpegJobStatus::Completed, _) => Ok(()),
+      (FfmpegJobStatus::Cancelled, _) => Err(FfmpegError::Cancelled),
+      (_, Some(e)) => Err(e.clone()),
+      (_, None) => Err(FfmpegError::Other { message: "ffmpeg job failed".to_string() }),
+    };
+
+    let job = self.update(|data| {
+      let job = data.jobs.iter_mut().find(|job| job.id == job_id)?;
+      job.status = status;
+      job.error = error;
+      if status == FfmpegJobStatus::Completed {
+        job.progress = 100.0;
+      }
+      job.finished_at = Some(now_millis());
+      let job = job.clone();
+      data.prune_finished();
+      Some(job)
+    });
+
+    if let Some(waiter) = self.waiters.lock().unwrap().remove(&job_id) {
+      let _ = waiter.send(result);
+    }
+    job
+  }
+
+  /// 取消等待中的任务, 任务不存在或已经开始运行时返回 None
+  fn cancel_pending(&self, job_id: u64) -> Option<FfmpegJob> {
+    let job = self.update(|data| {
+      let job = data.jobs.iter_mut().find(|job| job.id == job_id && job.status == FfmpegJobStatus::Pending)?;
+      job.status = FfmpegJobStatus::Cancelled;
+      job.finished_at = Some(now_millis());
+      let job = job.clone();
+      data.prune_finished();
+      Some(job)
+    })?;
+
+    if let Some(waiter) = self.waiters.lock().unwrap().remove(&job_id) {
+      let _ = waiter.send(Err(FfmpegError::Cancelled));
+    }
+    Some(job)
+  }
+
+  /// 触发运行中任务的取消令牌, 由调度器负责结束进程和清理
+  fn cancel_running(&self, job_id: u64) -> bool {
+    self.cancel_tokens.lock().unwrap().get(&job_id).map(CancellationToken::cancel).is_some()
+  }
+
+  /// 结束已取消的任务: 先向当前的 ffmpeg 进程发送 q, 等待任务自行结束, 超时后强制结束进程 <br>
+  /// 取消令牌已触发, 任务不会再启动新的 ffmpeg 进程 (见 FfmpegJobContext::spawn_ffmpeg)
+  async fn stop_job<T>(&self, job_id: u64, handle: &mut JoinHandle<T>) {
+    let mut child = self.children.lock().unwrap().remove(&job_id);
+    if let Some(child) = &mut child {
+      let _ = child.write(b"q");
+    }
+
+    if tokio::time::timeout(CANCEL_GRACE_PERIOD, &mut *handle).await.is_err() {
+      if let Some(child) = child {
+        let _ = child.kill();
+      }
+      handle.abort();
+    }
+  }
+}
+
+/// 任务上下文, 传给具体的 ffmpeg 任务, 用于发送进度和获取临时目录
+pub struct FfmpegJobContext {
+  pub app: AppHandle,
+  pub id: u64,
+  token: CancellationToken,
+}
+
+impl FfmpegJobContext {
+  /// 发送 ffmpeg-progress 事件, 同时更新队列中的任务进度
+  pub fn emit_progress(&self, payload: ProgressPayload) {
+    self.app.state::<FfmpegQueue>().set_progress(self.id, payload.progress);
+    let _ = self.app.emit("ffmpeg-progress", payload);
+  }
+
+  /// 任务独占的临时目录, 并发运行的任务之间不会互相覆盖或删除临时文件
+  pub fn temp_dir(&self) -> Result<PathBuf, String> {
+    let dir = job_temp_dir(&self.app, self.id)?;
+    std::fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+    Ok(dir)
+  }
+
+  /// 任务是否已被取消
+  pub fn is_cancelled(&self) -> bool {
+    self.token.is_cancelled()
+  }
+
+  /// 启动 ffmpeg sidecar, 进程句柄保存在队列中, 取消任务时用于结束进程 <br>
+  /// 会自动加上 -progress pipe:1 -nostats, 进度从 stdout 读取; 任务已取消时不再启动
+  pub fn spawn_ffmpeg<I, S>(&self, args: I) -> Result<Receiver<CommandEvent>, FfmpegError>
+  where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+  {
+    // 持有锁检查取消状态, 保证 stop_job 取走进程句柄之后不会再有新的进程
+    let queue = self.app.state::<FfmpegQueue>();
+    let mut children = queue.children.lock().unwrap();
+    if self.is_cancelled() {
+      return Err(FfmpegError::Cancelled);
+    }
+
+    let (rx, child) = self
+      .app
+      .shell()
+      .sidecar("ffmpeg")
+      .map_err(|e| format!("Failed to create sidecar: {}", e))?
+      .args(PROGRESS_ARGS)
+      .args(args)
+      .spawn()
+      .map_err(|e| e.to_string())?;
+
+    children.insert(self.id, child);
+    Ok(rx)
+  }
+}
+
+fn job_temp_dir(app: &AppHandle, job_id: u64) -> Result<PathBuf, String> {
+  Ok(get_cache_temp_dir(app.clone())?.join(format!("ffmpeg_job_{}", job_id)))
+}
+
+/// ffmpeg-cancelled 事件数据结构
+#[derive(Clone, Serialize)]
+struct CancelledPayload {
+  job_id: u64,
+}
+
+/// 启动调度器, 应用启动时调用一次
+pub fn spawn_ffmpeg_scheduler(app: AppHandle) {
+  async_runtime::spawn(async move {
+    let queue = app.state::<FfmpegQueue>();
+    loop {
+      while let Some(job) = queue.next_runnable() {
+        let app = app.clone();
+        let token = CancellationToken::new();
+        queue.cancel_tokens.lock().unwrap().insert(job.id, token.clone());
+
+        async_runtime::spawn(async move {
+          emit_job(&app, &job);
+          let ctx = FfmpegJobContext { app: app.clone(), id: job.id, token: token.clone() };
+          let kind = job.kind.clone();
+          // 任务放在单独的 task 中运行, 取消时直接 abort; 任务不能 panic (release 构建 panic = "abort"), 错误都通过返回值传递
+          let mut handle = async_runtime::spawn(async move { run_ffmpeg_job(&ctx, &kind).await });
+          let outcome = token.run_until_cancelled(&mut handle).await;
+
+          let queue = app.state::<FfmpegQueue>();
+          let (status, error) = match outcome {
+            Some(Ok(Ok(()))) => (FfmpegJobStatus::Completed, None),
+            Some(Ok(Err(e))) => (FfmpegJobStatus::Failed, Some(e)),
+            Some(Err(e)) => (
+              FfmpegJobStatus::Failed,
+              Some(FfmpegError::Other { message: format!("ffmpeg job task failed: {}", e) }),
+            ),
+            None => {
+              queue.stop_job(job.id, &mut handle).await;
+              // 取消时 ffmpeg 写出的文件一定是不完整的, 一并删除; 输出路径上原有的文件不受影响
+              let _ = std::fs::remove_file(job.kind.partial_output_path(job.id));
+              (FfmpegJobStatus::Cancelled, None)
+            }
+          };
+          if let Some(e) = &error {
+            log::error!("ffmpeg job {} failed: {}", job.id, e);
+          }
+          if status != FfmpegJobStatus::Completed {
+            remove_job_temp_dir(&app, job.id);
+          }
+
+          if let Some(job) = queue.finish(job.id, status, error) {
+            emit_job(&app, &job);
+          }
+          if status == FfmpegJobStatus::Cancelled {
+            let _ = app.emit("ffmpeg-cancelled", CancelledPayload { job_id: job.id });
+          }
+        });
+      }
+      queue.notify.notified().await;
+    }
+  });
+}
+
+/// 删除失败或被取消任务的临时文件, 正常结束的任务由各自的执行函数清理
+fn remove_job_temp_dir(app: &AppHandle, job_id: u64) {
+  if let Ok(dir) = job_temp_dir(app, job_id) {
+    let _ = std::fs::remove_dir_all(dir);
+  }
+}
+
+/// 任务状态变化时发送 ffmpeg-job 事件
+fn emit_job(app: &AppHandle, job: &FfmpegJob) {
+  let _ = app.emit("ffmpeg-job", job.clone());
+}
+
+/// 加入队列并等待任务结束, 供原有的 ffmpeg 命令使用
+pub async fn enqueue_and_wait(app: &AppHandle, kind: FfmpegJobKind) -> Result<(), FfmpegError> {
+  let queue = app.state::<FfmpegQueue>();
+  let (tx, rx) = oneshot::channel();
+
+  // 先登记等待者再入队, 任务不会在登记之前结束
+  let id = {
+    let mut waiters = queue.waiters.lock().unwrap();
+    let id = queue.enqueue(kind);
+    waiters.insert(id, tx);
+    id
+  };
+  if let Some(job) = queue.jobs().into_iter().find(|job| job.id == id) {
+    emit_job(app, &job);
+  }
+
+  rx.await.map_err(|_| FfmpegError::Other { message: "ffmpeg job dropped".to_string() })?
+}
+
+/// 添加任务, 立即返回任务 ID, 任务状态通过 ffmpeg-job 事件通知
+#[tauri::command]
+pub fn enqueue_ffmpeg_job(app: AppHandle, kind: FfmpegJobKind, state: State<'_, FfmpegQueue>) -> u64 {
+  let id = state.enqueue(kind);
+  if let Some(job) = state.jobs().into_iter().find(|job| job.id == id) {
+    emit_job(&app, &job);
+  }
+  id
+}
+
+/// 列出队列中的任务 (包括本次运行中最近结束的任务, 见 MAX_FINISHED_JOBS)
+#[tauri::command]
+pub fn list_ffmpeg_jobs(state: State<'_, FfmpegQueue>) -> Vec<FfmpegJob> {
+  state.jobs()
+}
+
+/// 调整等待中的任务的位置, index 为在等待中任务里的新位置 (0 表示下一个运行)
+#[tauri::command]
+pub fn reorder_ffmpeg_job(job_id: u64, index: usize, state: State<'_, FfmpegQueue>) -> Result<(), String> {
+  state.update(|data| data.reorder(job_id, index))
+}
+
+/// 暂停队列: 正在运行的任务继续执行, 不再启动新任务
+#[tauri::command]
+pub fn pause_ffmpeg_queue(state: State<'_, FfmpegQueue>) {
+  state.update(|data| data.paused = true);
+}
+
+/// 恢复队列
+#[tauri::command]
+pub fn resume_ffmpeg_queue(state: State<'_, FfmpegQueue>) {
+  state.update(|data| data.paused = false);
+}
+
+/// 取消任务, 运行中的 ffmpeg 会被结束, 临时文件和不完整的输出文件会被删除 <br>
+/// 取消后发送 ffmpeg-cancelled 事件 (而不是 ffmpeg-complete), 任务不存在或已结束时返回 false
+#[tauri::command]
+pub fn cancel_ffmpeg_job(app: AppHandle, job_id: u64, state: State<'_, FfmpegQueue>) -> bool {
+  if state.cancel_running(job_id) {
+    return true;
+  }
+
+  match state.cancel_pending(job_id) {
+    Some(job) => {
+      emit_job(&app, &job);
+      let _ = app.emit("ffmpeg-cancelled", CancelledPayload { job_id });
+      true
+    }
+    None => false,
+  }
+}
+
+/// 设置同时运行的最大任务数, 最小为 1
+#[tauri::command]
+pub fn set_ffmpeg_max_concurrency(max: usize, state: State<'_, FfmpegQueue>) {
+  state.update(|data| data.max_concurrency = max.max(1));
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn job(id: u64, status: FfmpegJobStatus, finished_at: Option<u64>) -> FfmpegJob {
+    FfmpegJob {
+      id,
+      kind: FfmpegJobKind::Convert { video_path: String::new(), output_path: String::new() },
+      status,
+      progress: 0.0,
+      error: None,
+      created_at: 0,
+      started_at: None,
+      finished_at,
+    }
+  }
+
+  fn ids(data: &QueueData) -> Vec<u64> {
+    data.jobs.iter().map(|job| job.id).collect()
+  }
+
+  #[test]
+  fn reorder_index_counts_pending_jobs_only() {
+    use FfmpegJobStatus::*;
+    let mut data = QueueData {
+      jobs: vec![
+        job(1, Completed, Some(1)),
+        job(2, Failed, Some(2)),
+        job(3, Running, None),
+        job(4, Pending, None),
+        job(5, Pending, None),
+        job(6, Pending, None),
+      ],
+      ..Default::default()
+    };
+
+    data.reorder(6, 0).unwrap();
+    assert_eq!(ids(&data), [1, 2, 3, 6, 4, 5]);
+    data.reorder(6, 1).unwrap();
+    assert_eq!(ids(&data), [1, 2, 3, 4, 6, 5]);
+    data.reorder(4, 10).unwrap();
+    assert_eq!(ids(&data), [1, 2, 3, 6, 5, 4]);
+    assert!(data.reorder(1, 0).is_err());
+    assert!(data.reorder(3, 0).is_err());
+  }
+
+  #[test]
+  fn prune_keeps_latest_finished_jobs() {
+    let mut data = QueueData::default();
+    data.jobs.push(job(1, FfmpegJobStatus::Pending, None));
+    for id in 2..MAX_FINISHED_JOBS as u64 + 7 {
+      data.jobs.push(job(id, FfmpegJobStatus::Completed, Some(id)));
+    }
+
+    data.prune_finished();
+    assert_eq!(data.jobs.len(), MAX_FINISHED_JOBS + 1);
+    assert_eq!(data.jobs[0].id, 1);
+    assert_eq!(data.jobs[1].id, 7);
+  }
+}
@@ -1,6 +1,13 @@
+pub mod container;
 pub mod crypto;
 pub mod files;
 pub mod font;
 pub mod gpu;
+pub mod hls;
+pub mod job;
+pub mod keys;
+pub mod range;
 pub mod server;
+pub mod stats;
+pub mod transcode;
 pub mod window;
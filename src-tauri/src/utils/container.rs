@@ -0,0 +1,688 @@
+// 加密文件容器格式 (所有整数均为小端序)
+//
+// | 偏移 | 长度 | 字段                                   |
+// |------|------|----------------------------------------|
+// | 0    | 8    | 魔数 "RIGELENC"                        |
+// | 8    | 1    | 格式版本                               |
+// | 9    | 1    | KDF 算法 (1 = Argon2id)                |
+// | 10   | 4    | 文件头总长度 (含扩展区)                |
+// | 14   | 12   | Argon2 参数 m_cost / t_cost / p_cost   |
+// | 26   | 16   | 盐值                                   |
+// | 42   | 16   | nonce 前缀                             |
+// | 58   | 4    | 明文分块大小                           |
+// | 62   | 8    | 明文总大小                             |
+// | 70   | ..   | 扩展区: [tag u8][len u32][value]...    |
+//
+// 扩展记录:
+// - EXT_KEY_CHECK: 密钥校验值, 解密前用于判断密码是否正确
+// - EXT_METADATA: 原始文件元数据 (JSON), 使用子密钥单独加密, 无需解密正文即可读取
+// - EXT_PLAIN_DIGEST: 无内容, 表示文件末尾带有明文 SHA-256 校验记录
+// 读取时忽略未知的扩展记录。
+// 文件头之后是按顺序排列的密文分块, 每块 = 明文分块 + 16 字节认证标签, 最后一块可以不满。
+// 带 EXT_PLAIN_DIGEST 时最后一块之后还有一个以 DIGEST_INDEX 为序号加密的明文 SHA-256 (32 + 16 字节)。
+// 整个文件头作为每个分块的附加认证数据 (AAD), 篡改文件头或截断文件都会导致解密失败。
+// 分块大小固定, 因此可以直接由明文偏移算出分块位置, 支持随机访问解密。
+use std::{path::Path, time::UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{
+  fs::File,
+  io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader, BufWriter},
+};
+
+use crate::utils::crypto::{
+  derive_key_async, encrypt_decrypt_at_offset, key_check, open_chunk, open_metadata, seal_chunk, seal_metadata,
+  verify_key_check, CryptoError, KdfParams, KEY_CHECK_LEN, KEY_LEN, LEGACY_NONCE, NONCE_PREFIX_LEN, SALT_LEN, TAG_LEN,
+};
+
+pub const MAGIC: &[u8; 8] = b"RIGELENC";
+pub const FORMAT_VERSION: u8 = 1;
+pub const KDF_ARGON2ID: u8 = 1;
+/// 默认明文分块大小 64 KiB, 与原来的读写缓冲区一致
+pub const DEFAULT_CHUNK_SIZE: u32 = 64 * 1024;
+
+const FIXED_LEN: usize = 70;
+/// 文件头长度上限, 防止损坏的文件头申请过大内存
+const MAX_HEADER_LEN: usize = 1024 * 1024;
+const MAX_CHUNK_SIZE: u32 = 16 * 1024 * 1024;
+
+/// 扩展记录: 密钥校验值
+const EXT_KEY_CHECK: u8 = 1;
+/// 扩展记录: 加密的原始文件元数据
+const EXT_METADATA: u8 = 2;
+/// 扩展记录: 文件末尾带有明文 SHA-256 校验记录
+const EXT_PLAIN_DIGEST: u8 = 3;
+
+/// 明文 SHA-256 校验记录使用的分块序号, 不会与正常分块冲突
+const DIGEST_INDEX: u64 = u64::MAX;
+const DIGEST_LEN: usize = 32;
+
+/// 原始文件元数据, 加密后保存在文件头中
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileMetadata {
+  /// 原始文件名
+  pub name: String,
+  /// 由文件内容识别的 MIME 类型, 无法识别时为 None
+  pub mime: Option<String>,
+  /// 原始文件大小
+  pub size: u64,
+  /// 修改时间 (Unix 毫秒时间戳)
+  pub modified: Option<u64>,
+}
+
+impl FileMetadata {
+  /// 读取明文文件的元数据
+  pub fn from_path(path: &Path) -> Result<Self, String> {
+    let metadata = std::fs::metadata(path).map_err(|e| e.to_string())?;
+    let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let mime = infer::get_from_path(path).ok().flatten().map(|t| t.mime_type().to_string());
+    let modified =
+      metadata.modified().ok().and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map(|d| d.as_millis() as u64);
+
+    Ok(Self { name, mime, size: metadata.len(), modified })
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct ContainerHeader {
+  pub version: u8,
+  pub kdf: KdfParams,
+  pub salt: [u8; SALT_LEN],
+  pub nonce: [u8; NONCE_PREFIX_LEN],
+  pub chunk_size: u32,
+  pub plain_size: u64,
+  pub key_check: Option<[u8; KEY_CHECK_LEN]>,
+  /// 加密的元数据记录, 使用 metadata() 解密
+  pub metadata: Option<Vec<u8>>,
+  /// 文件末尾是否带有明文 SHA-256 校验记录
+  pub plain_digest: bool,
+  /// 文件头原始字节, 用作分块的 AAD
+  raw: Vec<u8>,
+}
+
+impl ContainerHeader {
+  /// key 必须是由同一组 salt 和 kdf 参数派生的密钥, 用于生成密钥校验值
+  pub fn new(
+    kdf: KdfParams,
+    salt: [u8; SALT_LEN],
+    nonce: [u8; NONCE_PREFIX_LEN],
+    plain_size: u64,
+    key: &[u8; KEY_LEN],
+  ) -> Self {
+    let mut header = Self {
+      version: FORMAT_VERSION,
+      kdf,
+      salt,
+      nonce,
+      chunk_size: DEFAULT_CHUNK_SIZE,
+      plain_size,
+      key_check: Some(key_check(key)),
+      metadata: None,
+      plain_digest: true,
+      raw: Vec::new(),
+    };
+    header.raw = header.encode();
+    header
+  }
+
+  /// 附加加密的原始文件元数据, 必须在写入文件之前调用
+  pub fn with_metadata(mut self, key: &[u8; KEY_LEN], metadata: &FileMetadata) -> Result<Self, String> {
+    let json = serde_json::to_vec(metadata).map_err(|e| e.to_string())?;
+    self.metadata = Some(seal_metadata(key, &json)?);
+    self.raw = self.encode();
+    Ok(self)
+  }
+
+  /// 解密文件头中的元数据, 没有元数据时返回 None
+  pub fn metadata(&self, key: &[u8; KEY_LEN]) -> Result<Option<FileMetadata>, String> {
+    let Some(sealed) = &self.metadata else {
+      return Ok(None);
+    };
+    let json = open_metadata(key, sealed)?;
+    serde_json::from_slice(&json).map(Some).map_err(|e| format!("invalid file metadata: {}", e))
+  }
+
+  fn encode(&self) -> Vec<u8> {
+    let mut extensions = Vec::new();
+    if let Some(check) = &self.key_check {
+      push_extension(&mut extensions, EXT_KEY_CHECK, check);
+    }
+    if let Some(metadata) = &self.metadata {
+      push_extension(&mut extensions, EXT_METADATA, metadata);
+    }
+    if self.plain_digest {
+      push_extension(&mut extensions, EXT_PLAIN_DIGEST, &[]);
+    }
+
+    let mut buf = Vec::with_capacity(FIXED_LEN + extensions.len());
+    buf.extend_from_slice(MAGIC);
+    buf.push(self.version);
+    buf.push(KDF_ARGON2ID);
+    buf.extend_from_slice(&((FIXED_LEN + extensions.len()) as u32).to_le_bytes());
+    buf.extend_from_slice(&self.kdf.m_cost.to_le_bytes());
+    buf.extend_from_slice(&self.kdf.t_cost.to_le_bytes());
+    buf.extend_from_slice(&self.kdf.p_cost.to_le_bytes());
+    buf.extend_from_slice(&self.salt);
+    buf.extend_from_slice(&self.nonce);
+    buf.extend_from_slice(&self.chunk_size.to_le_bytes());
+    buf.extend_from_slice(&self.plain_size.to_le_bytes());
+    buf.extend_from_slice(&extensions);
+    buf
+  }
+
+  /// 解析完整的文件头字节
+  fn decode(raw: Vec<u8>) -> Result<Self, String> {
+    let u32_at = |pos: usize| u32::from_le_bytes(raw[pos..pos + 4].try_into().unwrap());
+
+    let version = raw[8];
+    if version != FORMAT_VERSION {
+      return Err(format!("unsupported encrypted file version: {}", version));
+    }
+    if raw[9] != KDF_ARGON2ID {
+      return Err(format!("unsupported kdf algorithm: {}", raw[9]));
+    }
+
+    let kdf = KdfParams { m_cost: u32_at(14), t_cost: u32_at(18), p_cost: u32_at(22) };
+    let salt = raw[26..42].try_into().unwrap();
+    let nonce = raw[42..58].try_into().unwrap();
+    let chunk_size = u32_at(58);
+    let plain_size = u64::from_le_bytes(raw[62..70].try_into().unwrap());
+
+    if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+      return Err(format!("invalid chunk size: {}", chunk_size));
+    }
+
+    let mut key_check = None;
+    let mut metadata = None;
+    let mut plain_digest = false;
+    let mut rest = &raw[FIXED_LEN..];
+    while !rest.is_empty() {
+      if rest.len() < 5 {
+        return Err("invalid header extension".to_string());
+      }
+      let tag = rest[0];
+      let len = u32::from_le_bytes(rest[1..5].try_into().unwrap()) as usize;
+      let value = rest.get(5..5 + len).ok_or("invalid header extension".to_string())?;
+
+      match tag {
+        EXT_KEY_CHECK => key_check = Some(value.try_into().map_err(|_| "invalid key check length".to_string())?),
+        EXT_METADATA => metadata = Some(value.to_vec()),
+        EXT_PLAIN_DIGEST => plain_digest = true,
+        _ => {}
+      }
+      rest = &rest[5 + len..];
+    }
+
+    Ok(Self { version, kdf, salt, nonce, chunk_size, plain_size, key_check, metadata, plain_digest, raw })
+  }
+
+  /// 从文件开头读取文件头, 魔数不匹配时返回 None (旧格式: 仅有盐值的文件头)
+  pub async fn read_from(file: &mut File) -> Result<Option<Self>, String> {
+    file.rewind().await.map_err(|e| e.to_string())?;
+
+    let mut fixed = vec![0u8; FIXED_LEN];
+    let n = read_full(file, &mut fixed).await.map_err(|e| e.to_string())?;
+    if n < MAGIC.len() || &fixed[..MAGIC.len()] != MAGIC {
+      file.rewind().await.map_err(|e| e.to_string())?;
+      return Ok(None);
+    }
+    if n < FIXED_LEN {
+      return Err("encrypted file header is truncated".to_string());
+    }
+
+    let header_len = u32::from_le_bytes(fixed[10..14].try_into().unwrap()) as usize;
+    if !(FIXED_LEN..=MAX_HEADER_LEN).contains(&header_len) {
+      return Err(format!("invalid header length: {}", header_len));
+    }
+
+    let mut raw = fixed;
+    raw.resize(header_len, 0);
+    file.read_exact(&mut raw[FIXED_LEN..]).await.map_err(|e| e.to_string())?;
+
+    Self::decode(raw).map(Some)
+  }
+
+  /// 文件头原始字节
+  pub fn as_bytes(&self) -> &[u8] {
+    &self.raw
+  }
+
+  pub fn header_len(&self) -> u64 {
+    self.raw.len() as u64
+  }
+
+  /// 分块数量, 空文件也保留一个空分块, 保证密码错误总能被检测到
+  pub fn chunk_count(&self) -> u64 {
+    self.plain_size.div_ceil(self.chunk_size as u64).max(1)
+  }
+
+  /// 第 index 块的明文长度
+  pub fn chunk_plain_len(&self, index: u64) -> usize {
+    let start = index * self.chunk_size as u64;
+    self.plain_size.saturating_sub(start).min(self.chunk_size as u64) as usize
+  }
+
+  /// 第 index 块密文在文件中的偏移
+  pub fn chunk_offset(&self, index: u64) -> u64 {
+    self.header_len() + index * (self.chunk_size as u64 + TAG_LEN as u64)
+  }
+
+  /// 明文 SHA-256 校验记录的长度
+  pub fn digest_len(&self) -> u64 {
+    if self.plain_digest {
+      (DIGEST_LEN + TAG_LEN) as u64
+    } else {
+      0
+    }
+  }
+}
+
+/// 加密文件头, 统一容器格式和旧格式的读取
+#[derive(Debug, Clone)]
+pub enum EncryptedHeader {
+  Container(ContainerHeader),
+  /// 旧格式: 文件头只有盐值, ChaCha20 使用固定的 LEGACY_NONCE, 没有认证
+  Legacy {
+    salt: [u8; SALT_LEN],
+    plain_size: u64,
+  },
+}
+
+impl EncryptedHeader {
+  /// 读取文件头, 读取后文件指针位于密文起始处
+  pub async fn read_from(file: &mut File) -> Result<Self, String> {
+    if let Some(header) = ContainerHeader::read_from(file).await? {
+      return Ok(Self::Container(header));
+    }
+
+    let mut salt = [0u8; SALT_LEN];
+    file.read_exact(&mut salt).await.map_err(|e| format!("Failed to read salt: {}", e))?;
+    let file_size = file.metadata().await.map_err(|e| e.to_string())?.len();
+
+    Ok(Self::Legacy { salt, plain_size: file_size - SALT_LEN as u64 })
+  }
+
+  /// 使用文件头中记录的盐值和 KDF 参数派生密钥, 并用密钥校验值验证密码 <br>
+  /// 旧格式没有校验值, 无法验证密码
+  pub async fn unlock(&self, secret: &[u8]) -> Result<[u8; KEY_LEN], CryptoError> {
+    let key = derive_key_async(secret, self.salt(), &self.kdf()).await?;
+    self.verify_key(&key)?;
+    Ok(key)
+  }
+
+  /// 校验已有的密钥 (例如批量处理时共享盐值的文件可以复用同一个密钥)
+  pub fn verify_key(&self, key: &[u8; KEY_LEN]) -> Result<(), CryptoError> {
+    match self {
+      Self::Container(ContainerHeader { key_check: Some(check), .. }) if !verify_key_check(key, check) => {
+        Err(CryptoError::WrongPassword)
+      }
+      _ => Ok(()),
+    }
+  }
+
+  pub fn salt(&self) -> &[u8; SALT_LEN] {
+    match self {
+      Self::Container(header) => &header.salt,
+      Self::Legacy { salt, .. } => salt,
+    }
+  }
+
+  pub fn kdf(&self) -> KdfParams {
+    match self {
+      Self::Container(header) => header.kdf,
+      Self::Legacy { .. } => KdfParams::default(),
+    }
+  }
+
+  pub fn plain_size(&self) -> u64 {
+    match self {
+      Self::Container(header) => header.plain_size,
+      Self::Legacy { plain_size, .. } => *plain_size,
+    }
+  }
+
+  pub fn is_legacy(&self) -> bool {
+    matches!(self, Self::Legacy { .. })
+  }
+}
+
+/// 顺序读取并解密加密文件, 兼容容器格式和旧格式
+pub struct DecryptReader {
+  reader: BufReader<File>,
+  header: EncryptedHeader,
+  key: [u8; KEY_LEN],
+  /// 容器格式: 下一个要读取的分块序号
+  index: u64,
+  /// 已解密的明文字节数
+  offset: u64,
+  /// 已解密明文的 SHA-256, 读完后与文件末尾的校验记录比较
+  hasher: Sha256,
+}
+
+impl DecryptReader {
+  /// 打开文件并验证密码, 密码错误时返回 CryptoError::WrongPassword
+  pub async fn open(path: impl AsRef<Path>, secret: &[u8]) -> Result<Self, CryptoError> {
+    let mut file = File::open(path).await.map_err(|e| e.to_string())?;
+    let header = EncryptedHeader::read_from(&mut file).await?;
+    let key = header.unlock(secret).await?;
+
+    Ok(Self::new(file, header, key))
+  }
+
+  /// 使用已读取的文件头和已校验的密钥创建, file 需位于密文起始处
+  pub fn new(file: File, header: EncryptedHeader, key: [u8; KEY_LEN]) -> Self {
+    Self { reader: BufReader::new(file), header, key, index: 0, offset: 0, hasher: Sha256::new() }
+  }
+
+  pub fn header(&self) -> &EncryptedHeader {
+    &self.header
+  }
+
+  /// 已校验的解密密钥
+  pub fn key(&self) -> &[u8; KEY_LEN] {
+    &self.key
+  }
+
+  /// 已解密的明文字节数
+  pub fn offset(&self) -> u64 {
+    self.offset
+  }
+
+  /// 读取下一段明文到 buf, 返回 false 表示已经读完 <br>
+  /// 带明文 SHA-256 校验记录的文件在读完时校验, 不一致时返回错误
+  pub async fn read_chunk(&mut self, buf: &mut Vec<u8>) -> Result<bool, String> {
+    match &self.header {
+      EncryptedHeader::Container(header) => {
+        let count = header.chunk_count();
+        if self.index > count {
+          return Ok(false);
+        }
+        if self.index == count {
+          self.index += 1;
+          if header.plain_digest {
+            buf.resize(DIGEST_LEN + TAG_LEN, 0);
+            self.reader.read_exact(buf).await.map_err(|_| "encrypted file is truncated".to_string())?;
+            open_chunk(&self.key, &header.nonce, DIGEST_INDEX, header.as_bytes(), buf)?;
+
+            if self.hasher.finalize_reset().as_slice() != buf.as_slice() {
+              return Err("plaintext SHA-256 mismatch: file is corrupted".to_string());
+            }
+          }
+          return Ok(false);
+        }
+
+        let len = header.chunk_plain_len(self.index);
+        buf.resize(len + TAG_LEN, 0);
+        self.reader.read_exact(buf).await.map_err(|_| "encrypted file is truncated".to_string())?;
+        open_chunk(&self.key, &header.nonce, self.index, header.as_bytes(), buf)?;
+        self.hasher.update(buf.as_slice());
+
+        self.index += 1;
+        self.offset += len as u64;
+        Ok(true)
+      }
+      EncryptedHeader::Legacy { .. } => {
+        buf.resize(DEFAULT_CHUNK_SIZE as usize, 0);
+        let n = read_full(&mut self.reader, buf).await.map_err(|e| e.to_string())?;
+        buf.truncate(n);
+        if n == 0 {
+          return Ok(false);
+        }
+
+        encrypt_decrypt_at_offset(buf, self.offset, &self.key, LEGACY_NONCE);
+
+        self.offset += n as u64;
+        Ok(true)
+      }
+    }
+  }
+}
+
+/// 顺序写入容器格式: 缓存明文, 凑满一个分块就加密写出
+pub struct EncryptWriter {
+  writer: BufWriter<File>,
+  header: ContainerHeader,
+  key: [u8; KEY_LEN],
+  index: u64,
+  pending: Vec<u8>,
+  written: u64,
+  hasher: Sha256,
+}
+
+impl EncryptWriter {
+  /// 写入文件头, 返回写入器
+  pub async fn create(file: File, header: ContainerHeader, key: [u8; KEY_LEN]) -> Result<Self, String> {
+    let mut writer = BufWriter::new(file);
+    writer.write_all(header.as_bytes()).await.map_err(|e| e.to_string())?;
+
+    let pending = Vec::with_capacity(header.chunk_size as usize + TAG_LEN);
+    Ok(Self { writer, header, key, index: 0, pending, written: 0, hasher: Sha256::new() })
+  }
+
+  pub async fn write(&mut self, mut data: &[u8]) -> Result<(), String> {
+    let chunk_size = self.header.chunk_size as usize;
+
+    while !data.is_empty() {
+      let take = (chunk_size - self.pending.len()).min(data.len());
+      self.pending.extend_from_slice(&data[..take]);
+      data = &data[take..];
+
+      if self.pending.len() == chunk_size {
+        self.seal_pending().await?;
+      }
+    }
+
+    Ok(())
+  }
+
+  async fn seal_pending(&mut self) -> Result<(), String> {
+    let len = self.pending.len() as u64;
+    self.hasher.update(&self.pending);
+    if self.index >= self.header.chunk_count() || self.written + len > self.header.plain_size {
+      return Err("input is larger than the size recorded in the header".to_string());
+    }
+
+    seal_chunk(
+      &self.key,
+      &self.header.nonce,
+      self.index,
+      self.header.as_bytes(),
+      &mut self.pending,
+    )?;
+    self.writer.write_all(&self.pending).await.map_err(|e| e.to_string())?;
+
+    self.pending.clear();
+    self.index += 1;
+    self.written += len;
+    Ok(())
+  }
+
+  /// 写出最后一个分块和明文 SHA-256 校验记录并刷新, 校验写入的明文大小与文件头一致
+  pub async fn finish(mut self) -> Result<(), String> {
+    if !self.pending.is_empty() || self.index == 0 {
+      self.seal_pending().await?;
+    }
+
+    if self.written != self.header.plain_size || self.index != self.header.chunk_count() {
+      return Err("input size changed during encryption".to_string());
+    }
+
+    if self.header.plain_digest {
+      let mut digest = self.hasher.finalize_reset().to_vec();
+      seal_chunk(&self.key, &self.header.nonce, DIGEST_INDEX, self.header.as_bytes(), &mut digest)?;
+      self.writer.write_all(&digest).await.map_err(|e| e.to_string())?;
+    }
+
+    self.writer.flush().await.map_err(|e| e.to_string())
+  }
+}
+
+fn push_extension(buf: &mut Vec<u8>, tag: u8, value: &[u8]) {
+  buf.push(tag);
+  buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+  buf.extend_from_slice(value);
+}
+
+/// 尽量读满 buf, 返回实际读取的字节数 (小于 buf 长度说明到达文件末尾)
+pub async fn read_full<R: AsyncRead + Unpin>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+  let mut filled = 0;
+  while filled < buf.len() {
+    let n = reader.read(&mut buf[filled..]).await?;
+    if n == 0 {
+      break;
+    }
+    filled += n;
+  }
+  Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::utils::crypto::{derive_key, generate_nonce_prefix, generate_salt, random_file_name, KdfPreset};
+
+  const SECRET: &[u8] = b"correct horse battery staple";
+  /// 测试使用最小的 Argon2 参数, 避免拖慢测试
+  const TEST_KDF: KdfParams = KdfParams { m_cost: 64, t_cost: 1, p_cost: 1 };
+
+  struct TempFile(std::path::PathBuf);
+
+  impl TempFile {
+    fn new() -> Self {
+      Self(std::env::temp_dir().join(format!("rigel_container_{}.enc", random_file_name())))
+    }
+
+    fn modify(&self, f: impl FnOnce(&mut Vec<u8>)) {
+      let mut bytes = std::fs::read(&self.0).unwrap();
+      f(&mut bytes);
+      std::fs::write(&self.0, bytes).unwrap();
+    }
+  }
+
+  impl Drop for TempFile {
+    fn drop(&mut self) {
+      let _ = std::fs::remove_file(&self.0);
+    }
+  }
+
+  fn sample(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 31 % 251) as u8).collect()
+  }
+
+  async fn encrypt(data: &[u8]) -> (TempFile, ContainerHeader, [u8; KEY_LEN]) {
+    let tmp = TempFile::new();
+    let salt = generate_salt();
+    let key = derive_key(SECRET, &salt, &TEST_KDF).unwrap();
+    let header = ContainerHeader::new(TEST_KDF, salt, generate_nonce_prefix(), data.len() as u64, &key);
+
+    let file = File::create(&tmp.0).await.unwrap();
+    let mut writer = EncryptWriter::create(file, header.clone(), key).await.unwrap();
+    writer.write(data).await.unwrap();
+    writer.finish().await.unwrap();
+    (tmp, header, key)
+  }
+
+  async fn decrypt(tmp: &TempFile, secret: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    let mut reader = DecryptReader::open(&tmp.0, secret).await?;
+    let mut out = Vec::new();
+    let mut buf = Vec::new();
+    while reader.read_chunk(&mut buf).await? {
+      out.extend_from_slice(&buf);
+    }
+    Ok(out)
+  }
+
+  #[tokio::test]
+  async fn roundtrip_at_chunk_boundaries() {
+    let chunk = DEFAULT_CHUNK_SIZE as usize;
+    for len in [0, 1, chunk, chunk + 1, chunk * 2] {
+      let data = sample(len);
+      let (tmp, header, _) = encrypt(&data).await;
+
+      let expected_len = header.header_len() + len as u64 + header.chunk_count() * TAG_LEN as u64 + header.digest_len();
+      assert_eq!(std::fs::metadata(&tmp.0).unwrap().len(), expected_len, "len {}", len);
+      assert_eq!(decrypt(&tmp, SECRET).await.unwrap(), data, "len {}", len);
+    }
+  }
+
+  #[tokio::test]
+  async fn flipped_ciphertext_byte_fails() {
+    let (tmp, header, _) = encrypt(&sample(1000)).await;
+    let pos = header.header_len() as usize + 10;
+    tmp.modify(|bytes| bytes[pos] ^= 1);
+
+    assert!(decrypt(&tmp, SECRET).await.is_err());
+  }
+
+  #[tokio::test]
+  async fn flipped_header_byte_fails() {
+    let (tmp, _, key) = encrypt(&sample(1000)).await;
+    // 修改盐值: 文件头仍能解析, 直接使用原密钥 (跳过密码校验), 分块只会因为 AAD 不匹配而解密失败
+    tmp.modify(|bytes| bytes[30] ^= 1);
+
+    let mut file = File::open(&tmp.0).await.unwrap();
+    let header = EncryptedHeader::read_from(&mut file).await.unwrap();
+    let mut reader = DecryptReader::new(file, header, key);
+    assert!(reader.read_chunk(&mut Vec::new()).await.is_err());
+  }
+
+  #[tokio::test]
+  async fn truncated_last_chunk_fails() {
+    let chunk = DEFAULT_CHUNK_SIZE as usize;
+    let (tmp, header, _) = encrypt(&sample(chunk + 100)).await;
+    let last_chunk = header.chunk_offset(header.chunk_count() - 1) as usize;
+    tmp.modify(|bytes| bytes.truncate(last_chunk));
+
+    assert!(decrypt(&tmp, SECRET).await.is_err());
+  }
+
+  #[tokio::test]
+  async fn swapped_chunks_fail() {
+    let chunk = DEFAULT_CHUNK_SIZE as usize;
+    let (tmp, header, _) = encrypt(&sample(chunk * 2)).await;
+    let (first, second) = (header.chunk_offset(0) as usize, header.chunk_offset(1) as usize);
+    let sealed = chunk + TAG_LEN;
+    tmp.modify(|bytes| {
+      let a = bytes[first..first + sealed].to_vec();
+      bytes.copy_within(second..second + sealed, first);
+      bytes[second..second + sealed].copy_from_slice(&a);
+    });
+
+    assert!(decrypt(&tmp, SECRET).await.is_err());
+  }
+
+  #[tokio::test]
+  async fn wrong_password_is_reported() {
+    let (tmp, _, _) = encrypt(&sample(1000)).await;
+
+    assert!(matches!(
+      decrypt(&tmp, b"wrong password").await,
+      Err(CryptoError::WrongPassword)
+    ));
+  }
+
+  #[tokio::test]
+  async fn tampered_digest_fails() {
+    let (tmp, header, _) = encrypt(&sample(1000)).await;
+    assert!(header.plain_digest);
+    tmp.modify(|bytes| *bytes.last_mut().unwrap() ^= 1);
+
+    assert!(decrypt(&tmp, SECRET).await.is_err());
+  }
+
+  #[test]
+  fn oversized_kdf_params_are_rejected() {
+    let salt = generate_salt();
+    for kdf in [
+      KdfParams { m_cost: 4 * 1024 * 1024, ..TEST_KDF },
+      KdfParams { t_cost: 100, ..TEST_KDF },
+      KdfParams { p_cost: 64, ..TEST_KDF },
+    ] {
+      assert!(derive_key(SECRET, &salt, &kdf).is_err(), "{:?}", kdf);
+    }
+    // paranoid 预设的迭代次数和并行度是上限, 仍然允许
+    let paranoid = KdfParams { m_cost: TEST_KDF.m_cost, ..KdfPreset::Paranoid.params() };
+    assert!(derive_key(SECRET, &salt, &paranoid).is_ok());
+  }
+}
@@ -1,20 +1,131 @@
-use chacha20::cipher::{KeyIvInit, StreamCipher, StreamCipherSeek};
-use chacha20::ChaCha20;
-
-use argon2::Argon2;
-
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chacha20::{
+  cipher::{KeyIvInit, StreamCipher, StreamCipherSeek},
+  ChaCha20,
+};
+use chacha20poly1305::{aead::AeadInPlace, KeyInit, XChaCha20Poly1305, XNonce};
+use hmac::{Hmac, Mac};
 use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use zeroize::Zeroizing;
 
-/// NONCE: 12 bytes 这个不能修改
-const NONCE: &[u8; 12] = b"unique-vicli";
+/// 旧格式文件使用的固定 nonce (12 bytes), 仅用于兼容解密, 新文件使用文件头中的随机 nonce
+pub const LEGACY_NONCE: &[u8; 12] = b"unique-vicli";
 pub const SALT_LEN: usize = 16;
+pub const KEY_LEN: usize = 32;
+/// 文件级随机 nonce 前缀长度, 与 8 字节分块序号拼成 24 字节的 XChaCha20 nonce
+pub const NONCE_PREFIX_LEN: usize = 16;
+/// Poly1305 认证标签长度
+pub const TAG_LEN: usize = 16;
+/// 密钥校验值长度 (HMAC-SHA256)
+pub const KEY_CHECK_LEN: usize = 32;
+const KEY_CHECK_LABEL: &[u8] = b"rigel key check v1";
+const NAME_KEY_LABEL: &[u8] = b"rigel file name v1";
+const METADATA_KEY_LABEL: &[u8] = b"rigel metadata v1";
+/// XChaCha20 nonce 长度
+const XNONCE_LEN: usize = 24;
+
+/// Argon2 参数上限, 防止恶意文件头声明超大的内存、迭代次数或并行度拖垮进程 <br>
+/// 迭代次数和并行度以 paranoid 预设为上限, 内存在 paranoid 预设 (256 MiB) 的基础上留出余量
+const MAX_M_COST: u32 = 512 * 1024;
+const MAX_T_COST: u32 = 4;
+const MAX_P_COST: u32 = 4;
+
+/// 加解密错误, 序列化为 { kind, message } 传给前端
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", content = "message", rename_all = "camelCase")]
+pub enum CryptoError {
+  /// 密码错误 (与文件头中的密钥校验值不匹配)
+  WrongPassword,
+  /// 其他错误 (IO、格式不支持、文件损坏等)
+  Other(String),
+}
+
+impl std::fmt::Display for CryptoError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      CryptoError::WrongPassword => write!(f, "wrong password"),
+      CryptoError::Other(msg) => write!(f, "{}", msg),
+    }
+  }
+}
+
+impl From<String> for CryptoError {
+  fn from(msg: String) -> Self {
+    CryptoError::Other(msg)
+  }
+}
+
+impl From<CryptoError> for String {
+  fn from(e: CryptoError) -> Self {
+    e.to_string()
+  }
+}
+
+/// Argon2id 派生参数
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct KdfParams {
+  pub m_cost: u32, // 内存 (KiB)
+  pub t_cost: u32, // 迭代次数
+  pub p_cost: u32, // 并行度
+}
+
+impl Default for KdfParams {
+  fn default() -> Self {
+    Self { m_cost: Params::DEFAULT_M_COST, t_cost: Params::DEFAULT_T_COST, p_cost: Params::DEFAULT_P_COST }
+  }
+}
 
-pub fn derive_key(password: &str, salt: &[u8]) -> [u8; 32] {
-  let mut key = [0u8; 32];
-  let argon2 = Argon2::default();
+/// 加密时可选的 Argon2 强度预设, 实际参数会写入文件头, 解密时不需要再指定
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum KdfPreset {
+  /// 8 MiB / 1 次迭代, 适合低配设备或临时文件
+  Fast,
+  /// 19 MiB / 2 次迭代, Argon2 默认参数, 与旧版本一致
+  #[default]
+  Balanced,
+  /// 256 MiB / 4 次迭代 / 4 线程, 派生一次约需数秒
+  Paranoid,
+}
+
+impl KdfPreset {
+  pub fn params(self) -> KdfParams {
+    match self {
+      KdfPreset::Fast => KdfParams { m_cost: 8 * 1024, t_cost: 1, p_cost: 1 },
+      KdfPreset::Balanced => KdfParams::default(),
+      KdfPreset::Paranoid => KdfParams { m_cost: 256 * 1024, t_cost: 4, p_cost: 4 },
+    }
+  }
+}
+
+/// secret: 密码字节或由密钥文件混合后的秘密值, 见 KeySource::secret
+pub fn derive_key(secret: &[u8], salt: &[u8], kdf: &KdfParams) -> Result<[u8; KEY_LEN], String> {
+  if kdf.m_cost > MAX_M_COST {
+    return Err(format!("kdf memory cost too large: {} KiB", kdf.m_cost));
+  }
+  if kdf.t_cost > MAX_T_COST {
+    return Err(format!("kdf time cost too large: {}", kdf.t_cost));
+  }
+  if kdf.p_cost > MAX_P_COST {
+    return Err(format!("kdf parallelism too large: {}", kdf.p_cost));
+  }
+  let params = Params::new(kdf.m_cost, kdf.t_cost, kdf.p_cost, Some(KEY_LEN)).map_err(|e| e.to_string())?;
+  let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
+
+  let mut key = [0u8; KEY_LEN];
   // 使用 Argon2 将密码和盐派生出 32 字节密钥
-  argon2.hash_password_into(password.as_bytes(), salt, &mut key).expect("Failed to derive key");
-  key
+  argon2.hash_password_into(secret, salt, &mut key).map_err(|e| e.to_string())?;
+  Ok(key)
+}
+
+/// 在阻塞线程池中执行 derive_key <br>
+/// Argon2 是 CPU 密集型计算 (paranoid 预设需要数秒), 不能在异步运行时的工作线程上执行
+pub async fn derive_key_async(secret: &[u8], salt: &[u8; SALT_LEN], kdf: &KdfParams) -> Result<[u8; KEY_LEN], String> {
+  let (secret, salt, kdf) = (Zeroizing::new(secret.to_vec()), *salt, *kdf);
+  tokio::task::spawn_blocking(move || derive_key(&secret, &salt, &kdf)).await.map_err(|e| e.to_string())?
 }
 
 pub fn generate_salt() -> [u8; SALT_LEN] {
@@ -23,8 +134,133 @@ pub fn generate_salt() -> [u8; SALT_LEN] {
   salt
 }
 
-pub fn encrypt_decrypt_at_offset(data: &mut [u8], offset: u64, key: &[u8; 32]) {
-  let mut cipher = ChaCha20::new(key.into(), NONCE.into());
+pub fn generate_nonce_prefix() -> [u8; NONCE_PREFIX_LEN] {
+  let mut nonce = [0u8; NONCE_PREFIX_LEN];
+  rand::rng().fill_bytes(&mut nonce);
+  nonce
+}
+
+/// 用 HMAC-SHA256 从主密钥派生不同用途的子密钥
+fn hmac_label(key: &[u8; KEY_LEN], label: &[u8]) -> [u8; 32] {
+  let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC accepts any key length");
+  mac.update(label);
+  mac.finalize().into_bytes().into()
+}
+
+/// 由密钥计算校验值写入文件头, 解密前据此判断密码是否正确, 校验值不会泄露密钥本身
+pub fn key_check(key: &[u8; KEY_LEN]) -> [u8; KEY_CHECK_LEN] {
+  hmac_label(key, KEY_CHECK_LABEL)
+}
+
+/// 常量时间比较密钥校验值
+pub fn verify_key_check(key: &[u8; KEY_LEN], expected: &[u8]) -> bool {
+  let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC accepts any key length");
+  mac.update(KEY_CHECK_LABEL);
+  mac.verify_slice(expected).is_ok()
+}
+
+/// 用 label 派生的子密钥加密一段独立数据, 输出 nonce || 密文 || 认证标签
+fn seal_blob(key: &[u8; KEY_LEN], label: &[u8], data: &[u8]) -> Result<Vec<u8>, String> {
+  let cipher = XChaCha20Poly1305::new(&hmac_label(key, label).into());
+  let mut nonce = [0u8; XNONCE_LEN];
+  rand::rng().fill_bytes(&mut nonce);
+
+  let mut buffer = data.to_vec();
+  cipher.encrypt_in_place(&nonce.into(), b"", &mut buffer).map_err(|_| "failed to encrypt data".to_string())?;
+
+  let mut sealed = nonce.to_vec();
+  sealed.extend_from_slice(&buffer);
+  Ok(sealed)
+}
+
+/// 解密 seal_blob 的输出, 密钥不匹配或数据被篡改时返回 None
+fn open_blob(key: &[u8; KEY_LEN], label: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+  if sealed.len() < XNONCE_LEN + TAG_LEN {
+    return None;
+  }
+
+  let cipher = XChaCha20Poly1305::new(&hmac_label(key, label).into());
+  let (nonce, ciphertext) = sealed.split_at(XNONCE_LEN);
+  let mut buffer = ciphertext.to_vec();
+  cipher.decrypt_in_place(XNonce::from_slice(nonce), b"", &mut buffer).ok()?;
+  Some(buffer)
+}
+
+/// 加密文件名, 输出 base64url(nonce || 密文), 可直接作为文件名使用
+pub fn encrypt_name(key: &[u8; KEY_LEN], name: &str) -> Result<String, String> {
+  Ok(URL_SAFE_NO_PAD.encode(seal_blob(key, NAME_KEY_LABEL, name.as_bytes())?))
+}
+
+/// 解密 encrypt_name 生成的文件名, 不是加密文件名或密钥不匹配时返回 None
+pub fn decrypt_name(key: &[u8; KEY_LEN], encoded: &str) -> Option<String> {
+  let data = URL_SAFE_NO_PAD.decode(encoded).ok()?;
+  String::from_utf8(open_blob(key, NAME_KEY_LABEL, &data)?).ok()
+}
+
+/// 加密文件头中的元数据记录
+pub fn seal_metadata(key: &[u8; KEY_LEN], data: &[u8]) -> Result<Vec<u8>, String> {
+  seal_blob(key, METADATA_KEY_LABEL, data)
+}
+
+/// 解密文件头中的元数据记录
+pub fn open_metadata(key: &[u8; KEY_LEN], sealed: &[u8]) -> Result<Vec<u8>, String> {
+  open_blob(key, METADATA_KEY_LABEL, sealed).ok_or("failed to decrypt file metadata".to_string())
+}
+
+/// 生成 128 位随机值的十六进制字符串
+fn random_hex() -> String {
+  let mut bytes = [0u8; 16];
+  rand::rng().fill_bytes(&mut bytes);
+  bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// 生成随机文件名 (32 位十六进制), 用于隐藏加密文件的原始名称
+pub fn random_file_name() -> String {
+  random_hex()
+}
+
+/// 生成随机访问令牌 (32 位十六进制), 用于流地址等不可猜测的标识
+pub fn random_token() -> String {
+  random_hex()
+}
+
+/// ChaCha20 流加密, 加解密为同一操作, 可从任意偏移开始
+pub fn encrypt_decrypt_at_offset(data: &mut [u8], offset: u64, key: &[u8; KEY_LEN], nonce: &[u8; 12]) {
+  let mut cipher = ChaCha20::new(key.into(), nonce.into());
   cipher.seek(offset);
   cipher.apply_keystream(data);
 }
+
+/// 分块 nonce = 文件 nonce 前缀 + 分块序号, 保证同一文件内每块 nonce 唯一且不可调换顺序
+fn chunk_nonce(prefix: &[u8; NONCE_PREFIX_LEN], index: u64) -> XNonce {
+  let mut nonce = [0u8; NONCE_PREFIX_LEN + 8];
+  nonce[..NONCE_PREFIX_LEN].copy_from_slice(prefix);
+  nonce[NONCE_PREFIX_LEN..].copy_from_slice(&index.to_le_bytes());
+  nonce.into()
+}
+
+/// 加密一个分块, 完成后 buffer 末尾追加 TAG_LEN 字节的认证标签
+pub fn seal_chunk(
+  key: &[u8; KEY_LEN],
+  prefix: &[u8; NONCE_PREFIX_LEN],
+  index: u64,
+  aad: &[u8],
+  buffer: &mut Vec<u8>,
+) -> Result<(), String> {
+  let cipher = XChaCha20Poly1305::new(key.into());
+  cipher.encrypt_in_place(&chunk_nonce(prefix, index), aad, buffer).map_err(|_| "failed to encrypt chunk".to_string())
+}
+
+/// 解密并校验一个分块, 成功后 buffer 只剩明文
+pub fn open_chunk(
+  key: &[u8; KEY_LEN],
+  prefix: &[u8; NONCE_PREFIX_LEN],
+  index: u64,
+  aad: &[u8],
+  buffer: &mut Vec<u8>,
+) -> Result<(), String> {
+  let cipher = XChaCha20Poly1305::new(key.into());
+  cipher
+    .decrypt_in_place(&chunk_nonce(prefix, index), aad, buffer)
+    .map_err(|_| format!("chunk {} authentication failed: wrong password or corrupted file", index))
+}
@@ -1,5 +1,9 @@
-use std::{fs, path::PathBuf};
+use std::{
+  fs,
+  path::{Path, PathBuf},
+};
 
+use serde::Deserialize;
 use tauri::{AppHandle, Manager, Runtime};
 
 use crate::get_file_path;
@@ -34,3 +38,159 @@ pub fn get_file_size(path: String) -> Result<u64, std::io::Error> {
   let metadata = fs::metadata(path).unwrap();
   Ok(metadata.len())
 }
+
+/// 递归列出目录下的所有文件 (按路径排序, 不跟随符号链接)
+pub fn walk_files(dir: &Path) -> Result<Vec<PathBuf>, String> {
+  let mut files = Vec::new();
+  let mut pending = vec![dir.to_path_buf()];
+
+  while let Some(current) = pending.pop() {
+    for entry in fs::read_dir(&current).map_err(|e| format!("{}: {}", current.display(), e))? {
+      let entry = entry.map_err(|e| e.to_string())?;
+      let file_type = entry.file_type().map_err(|e| e.to_string())?;
+      if file_type.is_dir() {
+        pending.push(entry.path());
+      } else if file_type.is_file() {
+        files.push(entry.path());
+      }
+    }
+  }
+
+  files.sort();
+  Ok(files)
+}
+
+/// 输出文件已存在时的处理方式
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum OverwritePolicy {
+  /// 返回错误, 不修改已有文件
+  #[default]
+  Fail,
+  /// 覆盖已有文件
+  Overwrite,
+  /// 自动改名为 "name (1).ext"
+  Rename,
+}
+
+impl OverwritePolicy {
+  /// 按策略确定实际的输出路径, 返回 (路径, 是否允许覆盖)
+  pub fn resolve(self, path: &Path) -> Result<(PathBuf, bool), String> {
+    match self {
+      Self::Overwrite => Ok((path.to_path_buf(), true)),
+      Self::Fail if path.exists() => Err(format!("output file already exists: {}", path.display())),
+      Self::Fail => Ok((path.to_path_buf(), false)),
+      Self::Rename => {
+        if !path.exists() {
+          return Ok((path.to_path_buf(), false));
+        }
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let ext = path.extension().map(|e| format!(".{}", e.to_string_lossy())).unwrap_or_default();
+        (1..)
+          .map(|n| path.with_file_name(format!("{} ({}){}", stem, n, ext)))
+          .find(|p| !p.exists())
+          .map(|p| (p, false))
+          .ok_or("no available file name".to_string())
+      }
+    }
+  }
+}
+
+/// 原子写入的输出文件: 先写入同目录下的临时文件, commit 时重命名到目标路径 <br>
+/// 未 commit 就被丢弃 (写入失败或任务被取消) 时删除临时文件, 目标路径不会留下写了一半的文件
+pub struct AtomicFile {
+  temp: PathBuf,
+  target: PathBuf,
+  overwrite: bool,
+  committed: bool,
+}
+
+impl AtomicFile {
+  pub fn new(target: &Path, overwrite: bool) -> Self {
+    let name = target.file_name().unwrap_or_default().to_string_lossy();
+    let temp = target.with_file_name(format!(".{}.{:016x}.part", name, rand::random::<u64>()));
+    Self { temp, target: target.to_path_buf(), overwrite, committed: false }
+  }
+
+  /// 创建临时文件
+  pub async fn create(&self) -> Result<tokio::fs::File, String> {
+    tokio::fs::File::create(&self.temp).await.map_err(|e| format!("{}: {}", self.temp.display(), e))
+  }
+
+  /// 将临时文件重命名为目标文件 <br>
+  /// 不允许覆盖时先检查再 rename 有竞态 (Unix 的 rename 会直接覆盖), 改为创建硬链接再删除临时文件,
+  /// 目标已存在时硬链接会失败; 不支持硬链接的文件系统 (如 FAT/exFAT) 退回检查后 rename
+  pub async fn commit(mut self) -> Result<(), String> {
+    if self.overwrite {
+      tokio::fs::rename(&self.temp, &self.target).await.map_err(|e| e.to_string())?;
+      self.committed = true;
+      return Ok(());
+    }
+
+    match tokio::fs::hard_link(&self.temp, &self.target).await {
+      Ok(()) => {
+        self.committed = true;
+        let _ = tokio::fs::remove_file(&self.temp).await;
+        Ok(())
+      }
+      Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Err(self.already_exists()),
+      Err(_) if self.target.exists() => Err(self.already_exists()),
+      Err(_) => {
+        tokio::fs::rename(&self.temp, &self.target).await.map_err(|e| e.to_string())?;
+        self.committed = true;
+        Ok(())
+      }
+    }
+  }
+
+  fn already_exists(&self) -> String {
+    format!("output file already exists: {}", self.target.display())
+  }
+}
+
+impl Drop for AtomicFile {
+  fn drop(&mut self) {
+    if !self.committed {
+      let _ = fs::remove_file(&self.temp);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tokio::io::AsyncWriteExt;
+
+  async fn write_atomic(target: &Path, overwrite: bool, data: &[u8]) -> (PathBuf, Result<(), String>) {
+    let output = AtomicFile::new(target, overwrite);
+    let temp = output.temp.clone();
+    let mut file = output.create().await.unwrap();
+    file.write_all(data).await.unwrap();
+    drop(file);
+    (temp, output.commit().await)
+  }
+
+  #[tokio::test]
+  async fn commit_never_replaces_existing_file_without_overwrite() {
+    let dir = std::env::temp_dir().join(format!("rigel_atomic_{:016x}", rand::random::<u64>()));
+    fs::create_dir_all(&dir).unwrap();
+    let target = dir.join("out.bin");
+
+    let (temp, result) = write_atomic(&target, false, b"first").await;
+    assert!(result.is_ok());
+    assert!(!temp.exists());
+    assert_eq!(fs::read(&target).unwrap(), b"first");
+
+    // 目标已存在: 返回错误, 原文件不变, 临时文件被删除
+    let (temp, result) = write_atomic(&target, false, b"second").await;
+    assert!(result.unwrap_err().contains("already exists"));
+    assert!(!temp.exists());
+    assert_eq!(fs::read(&target).unwrap(), b"first");
+
+    let (_, result) = write_atomic(&target, true, b"third").await;
+    assert!(result.is_ok());
+    assert_eq!(fs::read(&target).unwrap(), b"third");
+
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}
@@ -0,0 +1,121 @@
+// HLS 切片: 用 ffmpeg sidecar 把解密后的流实时切成 HLS 分段
+// 播放器只需要先拿到 index.m3u8 和第一个分段就能开始播放, 不受 moov 在文件末尾等容器布局的影响
+use std::path::{Path, PathBuf};
+
+use tauri::{async_runtime, AppHandle};
+use tauri_plugin_shell::{
+  process::{CommandChild, CommandEvent},
+  ShellExt,
+};
+
+use crate::utils::{files::get_cache_temp_dir, server::StreamCredential};
+
+/// 播放列表文件名
+pub const PLAYLIST_NAME: &str = "index.m3u8";
+/// 每个分段的目标时长 (秒)
+const SEGMENT_SECONDS: &str = "4";
+
+/// 所有流的 HLS 分段都缓存在临时目录下的 hls 文件夹中, 每个流一个子目录
+pub fn hls_root_dir(app: &AppHandle) -> Result<PathBuf, String> {
+  Ok(get_cache_temp_dir(app.clone())?.join("hls"))
+}
+
+/// 清理上次运行残留的分段 (应用异常退出时不会执行 Drop)
+pub fn clear_hls_cache(app: &AppHandle) {
+  if let Ok(dir) = hls_root_dir(app) {
+    let _ = std::fs::remove_dir_all(dir);
+  }
+}
+
+/// 判断是否是 ffmpeg 生成的文件名, 防止通过路径访问分段目录以外的文件
+pub fn is_hls_file_name(name: &str) -> bool {
+  name == PLAYLIST_NAME
+    || (name.starts_with("segment_")
+      && name.ends_with(".ts")
+      && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'.'))
+}
+
+/// 一个流的切片会话, 释放时结束 ffmpeg、吊销它的访问凭据并删除分段
+pub struct HlsSession {
+  dir: PathBuf,
+  child: Option<CommandChild>,
+  _credential: StreamCredential,
+}
+
+impl HlsSession {
+  /// 启动 ffmpeg 从 input_url 读取明文流并切片到 dir <br>
+  /// 输入直接使用本服务器的 /stream 地址, ffmpeg 可以通过 Range 请求跳到文件末尾读取 moov;
+  /// credential 为 input_url 中的内部凭据, 与会话同时释放
+  pub fn start(app: &AppHandle, input_url: &str, credential: StreamCredential, dir: PathBuf) -> Result<Self, String> {
+    std::fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+
+    let playlist = dir.join(PLAYLIST_NAME).to_string_lossy().into_owned();
+    let segments = dir.join("segment_%05d.ts").to_string_lossy().into_owned();
+    let args = [
+      "-hide_banner",
+      "-loglevel",
+      "error",
+      "-i",
+      input_url,
+      "-map",
+      "0:v:0?",
+      "-map",
+      "0:a:0?",
+      // 视频直接复制, 音频统一为 AAC (mpegts 不支持部分音频编码)
+      "-c:v",
+      "copy",
+      "-c:a",
+      "aac",
+      "-f",
+      "hls",
+      "-hls_time",
+      SEGMENT_SECONDS,
+      "-hls_list_size",
+      "0",
+      "-hls_playlist_type",
+      "event",
+      "-hls_flags",
+      "temp_file",
+      "-hls_segment_filename",
+      &segments,
+      &playlist,
+    ];
+
+    let (mut rx, child) = app
+      .shell()
+      .sidecar("ffmpeg")
+      .map_err(|e| format!("Failed to create sidecar: {}", e))?
+      .args(args)
+      .spawn()
+      .map_err(|e| e.to_string())?;
+
+    // 必须持续读取输出, 否则 ffmpeg 写满管道后会阻塞
+    async_runtime::spawn(async move {
+      while let Some(event) = rx.recv().await {
+        match event {
+          CommandEvent::Stderr(line) => log::warn!("hls ffmpeg: {}", String::from_utf8_lossy(&line).trim()),
+          CommandEvent::Terminated(status) => {
+            log::info!("hls ffmpeg exited with status {:?}", status.code);
+            break;
+          }
+          _ => {}
+        }
+      }
+    });
+
+    Ok(Self { dir, child: Some(child), _credential: credential })
+  }
+
+  pub fn dir(&self) -> &Path {
+    &self.dir
+  }
+}
+
+impl Drop for HlsSession {
+  fn drop(&mut self) {
+    if let Some(child) = self.child.take() {
+      let _ = child.kill();
+    }
+    let _ = std::fs::remove_dir_all(&self.dir);
+  }
+}
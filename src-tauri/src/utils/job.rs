@@ -0,0 +1,114 @@
+use std::{
+  collections::HashMap,
+  future::Future,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Mutex,
+  },
+};
+
+use serde::Serialize;
+use tauri::{async_runtime, AppHandle, Emitter, Manager};
+use tokio_util::sync::CancellationToken;
+
+/// 正在运行的加解密任务, 任务 ID -> 取消令牌
+#[derive(Default)]
+pub struct CryptoJobState {
+  next_id: AtomicU64,
+  jobs: Mutex<HashMap<u64, CancellationToken>>,
+}
+
+impl CryptoJobState {
+  /// 取消任务, 任务不存在 (已结束) 时返回 false
+  pub fn cancel(&self, job_id: u64) -> bool {
+    match self.jobs.lock().unwrap().get(&job_id) {
+      Some(token) => {
+        token.cancel();
+        true
+      }
+      None => false,
+    }
+  }
+}
+
+/// encrypt_progress 事件数据结构
+#[derive(Clone, Serialize)]
+struct JobProgressPayload {
+  job_id: u64,
+  progress: f64,
+}
+
+/// encrypt_error 事件数据结构
+#[derive(Clone, Serialize)]
+struct JobErrorPayload {
+  job_id: u64,
+  message: String,
+}
+
+/// encrypt_complete / encrypt_cancelled 事件数据结构
+#[derive(Clone, Serialize)]
+struct JobPayload {
+  job_id: u64,
+}
+
+/// 任务上下文, 用于发送带任务 ID 的事件
+#[derive(Clone)]
+pub struct CryptoJob {
+  pub id: u64,
+  app: AppHandle,
+}
+
+impl CryptoJob {
+  /// 按 1MB 粒度向前端发送进度
+  pub fn emit_progress(&self, offset: u64, total: u64) {
+    if total > 0 && (offset % (1024 * 1024) < 64 * 1024 || offset == total) {
+      self.emit_percent((offset as f64 / total as f64 * 100.0).round());
+    }
+  }
+
+  pub fn emit_percent(&self, progress: f64) {
+    let _ = self.app.emit("encrypt_progress", JobProgressPayload { job_id: self.id, progress });
+  }
+
+  /// 发送自定义事件, payload 需要自行带上 job_id
+  pub fn emit<S: Serialize + Clone>(&self, event: &str, payload: S) {
+    let _ = self.app.emit(event, payload);
+  }
+}
+
+/// 启动可取消的加解密任务, 返回任务 ID <br>
+/// 完成时发送 encrypt_complete, 失败时发送 encrypt_error, 取消时发送 encrypt_cancelled <br>
+/// 输出通过 AtomicFile 写入, 任务失败或取消时丢弃的临时文件会被删除
+pub fn spawn_crypto_job<F, Fut>(app: &AppHandle, task: F) -> u64
+where
+  F: FnOnce(CryptoJob) -> Fut,
+  Fut: Future<Output = Result<(), String>> + Send + 'static,
+{
+  let state = app.state::<CryptoJobState>();
+  let id = state.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+  let token = CancellationToken::new();
+  state.jobs.lock().unwrap().insert(id, token.clone());
+
+  let job = CryptoJob { id, app: app.clone() };
+  let future = task(job.clone());
+
+  async_runtime::spawn(async move {
+    // 取消时直接丢弃任务 future, 任务在下一个 await 点停止
+    let result = token.run_until_cancelled(future).await;
+    job.app.state::<CryptoJobState>().jobs.lock().unwrap().remove(&id);
+
+    match result {
+      Some(Ok(())) => job.emit("encrypt_complete", JobPayload { job_id: id }),
+      Some(Err(e)) => {
+        log::error!("Crypto job {} failed: {}", id, e);
+        job.emit("encrypt_error", JobErrorPayload { job_id: id, message: e });
+      }
+      None => {
+        log::info!("Crypto job {} cancelled", id);
+        job.emit("encrypt_cancelled", JobPayload { job_id: id });
+      }
+    }
+  });
+
+  id
+}
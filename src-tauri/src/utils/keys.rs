@@ -0,0 +1,105 @@
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use zeroize::Zeroizing;
+
+/// 系统密钥库中的服务名
+const KEYRING_SERVICE: &str = "rigel";
+const KEY_FILE_LABEL: &[u8] = b"rigel key file v1";
+/// 密钥文件最小长度
+const MIN_KEY_FILE_LEN: u64 = 32;
+/// 密钥文件最大长度, 防止误选大文件
+const MAX_KEY_FILE_LEN: u64 = 16 * 1024 * 1024;
+/// generate_key_file 生成的密钥文件长度
+const GENERATED_KEY_FILE_LEN: usize = 64;
+
+/// 密钥来源, 前端可以直接传密码字符串 (与旧接口兼容), 也可以传 { type, ... } 形式的引用
+#[derive(Clone, Deserialize)]
+#[serde(untagged)]
+pub enum KeySource {
+  /// 直接输入的密码
+  Password(String),
+  Reference(KeyReference),
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase", rename_all_fields = "camelCase")]
+pub enum KeyReference {
+  /// 仅使用密钥文件
+  KeyFile { path: String },
+  /// 密码 + 密钥文件, 两者缺一不可
+  PasswordAndKeyFile { password: String, key_file: String },
+  /// 保存在系统密钥库中的密码, 与直接输入该密码等价
+  Keyring { account: String },
+}
+
+impl KeySource {
+  /// 解析为参与 Argon2 派生的秘密值 <br>
+  /// 纯密码 (包括密钥库中的密码) 直接使用密码字节, 与旧版本派生结果一致;
+  /// 使用密钥文件时为 HMAC-SHA256(SHA-256(密钥文件), 标签 || 密码)
+  pub fn secret(&self) -> Result<Zeroizing<Vec<u8>>, String> {
+    match self {
+      Self::Password(password) => Ok(Zeroizing::new(password.as_bytes().to_vec())),
+      Self::Reference(KeyReference::KeyFile { path }) => mix_key_file("", path),
+      Self::Reference(KeyReference::PasswordAndKeyFile { password, key_file }) => mix_key_file(password, key_file),
+      Self::Reference(KeyReference::Keyring { account }) => {
+        let password = Zeroizing::new(keyring_entry(account)?.get_password().map_err(|e| match e {
+          keyring::Error::NoEntry => format!("no password saved in keyring for {}", account),
+          e => e.to_string(),
+        })?);
+        Ok(Zeroizing::new(password.as_bytes().to_vec()))
+      }
+    }
+  }
+
+  /// 在阻塞线程池中执行 secret <br>
+  /// 读取密钥文件和查询系统密钥库都是阻塞操作, 异步命令中使用这个版本
+  pub async fn secret_async(self) -> Result<Zeroizing<Vec<u8>>, String> {
+    tokio::task::spawn_blocking(move || self.secret()).await.map_err(|e| e.to_string())?
+  }
+}
+
+fn mix_key_file(password: &str, path: &str) -> Result<Zeroizing<Vec<u8>>, String> {
+  let len = std::fs::metadata(path).map_err(|e| format!("{}: {}", path, e))?.len();
+  if !(MIN_KEY_FILE_LEN..=MAX_KEY_FILE_LEN).contains(&len) {
+    return Err(format!("key file must be between {} bytes and 16 MiB", MIN_KEY_FILE_LEN));
+  }
+
+  let content = Zeroizing::new(std::fs::read(path).map_err(|e| format!("{}: {}", path, e))?);
+  let digest: Zeroizing<[u8; 32]> = Zeroizing::new(Sha256::digest(content.as_slice()).into());
+
+  let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(digest.as_slice()).expect("HMAC accepts any key length");
+  mac.update(KEY_FILE_LABEL);
+  mac.update(password.as_bytes());
+  Ok(Zeroizing::new(mac.finalize().into_bytes().to_vec()))
+}
+
+fn keyring_entry(account: &str) -> Result<keyring::Entry, String> {
+  keyring::Entry::new(KEYRING_SERVICE, account).map_err(|e| e.to_string())
+}
+
+/// 将密码保存到系统密钥库 (已存在时覆盖)
+pub fn save_keyring_password(account: &str, password: &str) -> Result<(), String> {
+  keyring_entry(account)?.set_password(password).map_err(|e| e.to_string())
+}
+
+/// 从系统密钥库删除密码, 不存在时忽略
+pub fn delete_keyring_password(account: &str) -> Result<(), String> {
+  match keyring_entry(account)?.delete_credential() {
+    Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+    Err(e) => Err(e.to_string()),
+  }
+}
+
+/// 生成随机密钥文件, 文件已存在时返回错误
+pub fn generate_key_file(path: &str) -> Result<(), String> {
+  use std::io::Write;
+
+  let mut content = Zeroizing::new(vec![0u8; GENERATED_KEY_FILE_LEN]);
+  rand::rng().fill_bytes(&mut content);
+
+  let mut file =
+    std::fs::OpenOptions::new().write(true).create_new(true).open(path).map_err(|e| format!("{}: {}", path, e))?;
+  file.write_all(&content).map_err(|e| e.to_string())
+}
@@ -0,0 +1,161 @@
+// HTTP Range 请求解析 (RFC 9110 第 14 节)
+//
+// Range: bytes=0-499          前 500 字节
+// Range: bytes=500-           从 500 到结尾
+// Range: bytes=-500           最后 500 字节 (后缀区间)
+// Range: bytes=0-0,-1         多个区间, 响应为 multipart/byteranges
+use axum::http::{header, HeaderMap};
+
+/// 单个请求最多接受的区间数量, 超过时忽略 Range 头, 防止构造大量小区间拖慢服务器
+const MAX_RANGES: usize = 16;
+
+/// Range 头的解析结果
+#[derive(Debug, PartialEq, Eq)]
+pub enum RangeRequest {
+  /// 没有 Range 头、语法无效或单位不是 bytes, 按规范返回完整内容
+  Full,
+  /// 所有区间都无法满足, 返回 416
+  Unsatisfiable,
+  /// 可以满足的区间 (闭区间 [start, end]), 按请求顺序排列
+  Ranges(Vec<(u64, u64)>),
+}
+
+/// 解析 Range 头, size 为资源大小
+pub fn parse_range(value: &str, size: u64) -> RangeRequest {
+  let Some((unit, specs)) = value.split_once('=') else {
+    return RangeRequest::Full;
+  };
+  if !unit.trim().eq_ignore_ascii_case("bytes") {
+    return RangeRequest::Full;
+  }
+
+  let specs: Vec<&str> = specs.split(',').map(str::trim).filter(|s| !s.is_empty()).collect();
+  if specs.is_empty() || specs.len() > MAX_RANGES {
+    return RangeRequest::Full;
+  }
+
+  let mut ranges = Vec::with_capacity(specs.len());
+  for spec in specs {
+    let Some((first, last)) = spec.split_once('-') else {
+      return RangeRequest::Full;
+    };
+    let (first, last) = (first.trim(), last.trim());
+
+    let range = if first.is_empty() {
+      // 后缀区间: 最后 n 个字节
+      let Ok(suffix) = last.parse::<u64>() else {
+        return RangeRequest::Full;
+      };
+      (suffix > 0 && size > 0).then(|| (size.saturating_sub(suffix), size - 1))
+    } else {
+      let Ok(start) = first.parse::<u64>() else {
+        return RangeRequest::Full;
+      };
+      let end = if last.is_empty() {
+        u64::MAX
+      } else {
+        match last.parse::<u64>() {
+          Ok(end) if end >= start => end,
+          // last-pos 小于 first-pos 时整个 Range 头无效
+          _ => return RangeRequest::Full,
+        }
+      };
+      (start < size).then(|| (start, end.min(size - 1)))
+    };
+
+    // 不能满足的区间忽略, 只要有一个可以满足就返回 206
+    if let Some(range) = range {
+      ranges.push(range);
+    }
+  }
+
+  if ranges.is_empty() {
+    RangeRequest::Unsatisfiable
+  } else {
+    RangeRequest::Ranges(ranges)
+  }
+}
+
+/// 判断 If-Range 条件是否成立, 不成立时应忽略 Range 头返回完整内容 <br>
+/// If-Range 可以是强 ETag 或者 Last-Modified 日期, 都要求与当前值完全一致
+pub fn if_range_matches(headers: &HeaderMap, etag: &str, last_modified: Option<&str>) -> bool {
+  let Some(value) = headers.get(header::IF_RANGE).and_then(|v| v.to_str().ok()) else {
+    return true;
+  };
+  let value = value.trim();
+
+  if value.starts_with('"') {
+    value == etag
+  } else if value.starts_with("W/") {
+    // 弱 ETag 不能用于 If-Range
+    false
+  } else {
+    last_modified == Some(value)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use axum::http::HeaderValue;
+
+  #[test]
+  fn parse_range_table() {
+    use RangeRequest::*;
+
+    let too_many = format!("bytes={}", vec!["0-0"; MAX_RANGES + 1].join(","));
+    let cases: Vec<(&str, u64, RangeRequest)> = vec![
+      ("bytes=0-499", 1000, Ranges(vec![(0, 499)])),
+      ("bytes=500-", 1000, Ranges(vec![(500, 999)])),
+      ("bytes=0-0,-1", 1000, Ranges(vec![(0, 0), (999, 999)])),
+      // 后缀大于资源大小时返回整个资源
+      ("bytes=-5000", 1000, Ranges(vec![(0, 999)])),
+      ("bytes=0-5000", 1000, Ranges(vec![(0, 999)])),
+      // 空资源没有可以满足的区间
+      ("bytes=0-", 0, Unsatisfiable),
+      ("bytes=-1", 0, Unsatisfiable),
+      // last < first 时整个 Range 头无效, 即使其他区间合法
+      ("bytes=0-1,5-4", 1000, Full),
+      (&too_many, 1000, Full),
+      ("items=0-1", 1000, Full),
+      ("bytes", 1000, Full),
+      ("bytes=abc", 1000, Full),
+      ("bytes=", 1000, Full),
+      // 不能满足的区间被忽略
+      ("bytes=0-1,2000-", 1000, Ranges(vec![(0, 1)])),
+      ("bytes=1000-,-0", 1000, Unsatisfiable),
+    ];
+
+    for (value, size, expected) in cases {
+      assert_eq!(parse_range(value, size), expected, "{} (size {})", value, size);
+    }
+  }
+
+  #[test]
+  fn if_range_table() {
+    const ETAG: &str = "\"abc\"";
+    const DATE: &str = "Wed, 21 Oct 2015 07:28:00 GMT";
+
+    let cases: Vec<(Option<&str>, bool)> = vec![
+      (None, true),
+      (Some("\"abc\""), true),
+      (Some("\"other\""), false),
+      // 弱 ETag 即使值相同也不能用于 If-Range
+      (Some("W/\"abc\""), false),
+      (Some(DATE), true),
+      (Some("Thu, 22 Oct 2015 07:28:00 GMT"), false),
+    ];
+
+    for (value, expected) in cases {
+      let mut headers = HeaderMap::new();
+      if let Some(value) = value {
+        headers.insert(header::IF_RANGE, HeaderValue::from_static(value));
+      }
+      assert_eq!(if_range_matches(&headers, ETAG, Some(DATE)), expected, "{:?}", value);
+    }
+
+    let mut headers = HeaderMap::new();
+    headers.insert(header::IF_RANGE, HeaderValue::from_static(DATE));
+    assert!(!if_range_matches(&headers, ETAG, None));
+  }
+}
@@ -1,43 +1,205 @@
-// 基于 Tokio 和 Hyper 构建。负责：定义路由 (/video)、解析 HTTP 请求头（如 Range）、封装 HTTP 响应（状态码、Header、Body）
+// 基于 Tokio 和 Hyper 构建。负责：定义路由 (/stream/{token})、解析 HTTP 请求头（如 Range）、封装 HTTP 响应（状态码、Header、Body）
 use axum::{
   body::Body,
-  extract::{Request, State},
+  extract::{Path, Query, Request, State},
   http::{header, HeaderMap, HeaderValue, StatusCode},
+  middleware::{self, Next},
   response::{IntoResponse, Response},
   routing::get,
   Router,
 };
 // 提供了 Bytes 和 BytesMut 类型。它们是比 Vec<u8> 更高效的字节容器，支持零拷贝切片。
 use bytes::{Bytes, BytesMut};
-use std::{io::SeekFrom, path::PathBuf, sync::Arc};
-use tauri::async_runtime;
+use serde::Deserialize;
+use std::{
+  collections::HashMap,
+  io::SeekFrom,
+  path::PathBuf,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex, RwLock,
+  },
+  time::{Duration, Instant, UNIX_EPOCH},
+};
+use subtle::ConstantTimeEq;
+use tauri::{async_runtime, AppHandle, Emitter};
 // 负责：监听 TCP 端口 (TcpListener)、异步读取文件 (File)、处理并发任务 (spawn)
 use tokio::io::{AsyncReadExt, AsyncSeekExt};
 use tokio::{fs::File, sync::oneshot};
 
-use crate::utils::crypto::{derive_key, encrypt_decrypt_at_offset, SALT_LEN};
+use crate::shell::ffmpeg::{select_best_encoder, EncoderPreset};
+use crate::utils::{
+  container::{ContainerHeader, EncryptedHeader},
+  crypto::{
+    encrypt_decrypt_at_offset, open_chunk, random_token, CryptoError, KEY_LEN, LEGACY_NONCE, SALT_LEN, TAG_LEN,
+  },
+  gpu::get_gpu_info,
+  hls::{clear_hls_cache, hls_root_dir, is_hls_file_name, HlsSession, PLAYLIST_NAME},
+  range::{if_range_matches, parse_range, RangeRequest},
+  stats::{StreamStats, StreamStatsSnapshot},
+  transcode::transcode_stream,
+};
 // 提供了 StreamExt trait。Rust 标准库对 Stream（异步流）的支持还很少
-use futures_util::StreamExt;
+use futures_util::{stream, stream::BoxStream, StreamExt};
 // 它提供了 ReaderStream，把“文件读取器”转换成了“数据流”，这样才能通过 HTTP 发送出去
-use tokio_util::io::ReaderStream;
+use tokio_util::{io::ReaderStream, sync::CancellationToken};
+use zeroize::Zeroizing;
 
-pub struct AppState {
-  pub password: String,
-  pub video_path: PathBuf,
+/// 文件的解密方式
+#[derive(Clone)]
+enum Cipher {
+  /// 容器格式: 按分块 AEAD 解密, 可随机访问任意分块
+  Container { header: Arc<ContainerHeader>, key: Zeroizing<[u8; KEY_LEN]> },
+  /// 旧格式: 盐值 + ChaCha20 流, 可直接 seek 到任意字节
+  Legacy { key: Zeroizing<[u8; KEY_LEN]> },
+  /// 未加密的普通文件, 原样发送
+  Plain,
 }
 
-/// 启动内部流媒体服务器 <br>
-/// shutdown_rx: 这是一个“遥控器”。当你的主程序（比如 Tauri 窗口关闭时）发送信号，这个服务器就会优雅退出，停止占用资源。
-pub async fn start_server(password: String, video_path: PathBuf, shutdown_rx: oneshot::Receiver<()>) -> u16 {
-  let state = Arc::new(AppState { password, video_path });
+/// 一个已注册的流
+pub struct StreamEntry {
+  pub path: PathBuf,
+  /// 注册时派生好的密钥, 之后的请求不再运行 Argon2; 密码本身不保存
+  cipher: Cipher,
+  /// 明文大小
+  size: u64,
+  /// 响应的 Content-Type, 注册时通过 detect_content_type 确定
+  pub content_type: String,
+  /// 访问统计
+  pub stats: Arc<StreamStats>,
+  /// HLS 切片会话, 第一次请求播放列表时启动, 流注销后随之清理
+  hls: Mutex<Option<HlsSession>>,
+  /// 流注销或服务器关闭时取消, 结束仍在传输的响应, 释放其中的密钥副本
+  closed: CancellationToken,
+}
+
+impl StreamEntry {
+  /// 读取文件头、派生密钥 (同时校验密码) 并识别文件类型, secret 为 None 表示未加密的普通文件
+  pub async fn open(path: PathBuf, secret: Option<&[u8]>) -> Result<Self, CryptoError> {
+    let mut file = File::open(&path).await.map_err(|e| e.to_string())?;
+    let (cipher, size) = open_cipher(&mut file, secret).await?;
+    let content_type = detect_content_type(file, &cipher, size).await?;
+
+    Ok(Self {
+      path,
+      cipher,
+      size,
+      content_type,
+      stats: Default::default(),
+      hls: Mutex::new(None),
+      closed: CancellationToken::new(),
+    })
+  }
+
+  /// 结束这个流上所有仍在传输的响应 <br>
+  /// 暂停的播放器会一直保持连接, 不主动结束的话响应中的密钥副本不会被释放
+  pub fn close(&self) {
+    self.closed.cancel();
+  }
+}
+
+/// 流注册表: 令牌 -> 流, 服务器和命令共享
+pub type StreamRegistry = Arc<RwLock<HashMap<String, Arc<StreamEntry>>>>;
+
+/// 嗅探文件类型时解密的明文长度, 足够覆盖常见音视频和图片格式的魔数
+const SNIFF_LEN: u64 = 8192;
+/// 无法识别文件类型时使用的 Content-Type
+const FALLBACK_CONTENT_TYPE: &str = "application/octet-stream";
+/// 等待 ffmpeg 生成播放列表或分段的最长时间
+const HLS_WAIT_TIMEOUT: Duration = Duration::from_secs(30);
+/// stream_stats 事件的发送间隔
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 访问令牌的查询参数名, 也可以通过 X-Rigel-Token 或 Authorization: Bearer 请求头传递
+pub const ACCESS_TOKEN_PARAM: &str = "access_token";
+const ACCESS_TOKEN_HEADER: &str = "x-rigel-token";
+
+/// 允许的 Origin: Tauri 各平台的 webview 和开发服务器
+const ALLOWED_ORIGINS: &[&str] =
+  &["tauri://localhost", "http://tauri.localhost", "https://tauri.localhost", "http://localhost:1420"];
+
+/// 服务器访问控制配置, 每次启动服务器时生成新的访问令牌
+pub struct ServerConfig {
+  /// 本次会话的访问令牌, 所有请求都必须携带
+  pub access_token: String,
+  /// 是否校验 Origin 请求头, 拒绝来自普通网页的跨站请求
+  pub check_origin: AtomicBool,
+}
+
+/// 交给 ffmpeg 的内部凭据: 凭据 -> 允许读取的流令牌
+type CredentialRegistry = Arc<Mutex<HashMap<String, String>>>;
+
+/// ffmpeg 读取 /stream 地址使用的内部凭据, 只对一个流有效, 释放时吊销 <br>
+/// ffmpeg 的命令行参数对本机其他进程可见 (ps、/proc), 不能把会话访问令牌传给它
+pub struct StreamCredential {
+  value: String,
+  registry: CredentialRegistry,
+}
+
+impl Drop for StreamCredential {
+  fn drop(&mut self) {
+    self.registry.lock().unwrap().remove(&self.value);
+  }
+}
+
+struct AppState {
+  app: AppHandle,
+  streams: StreamRegistry,
+  config: Arc<ServerConfig>,
+  credentials: CredentialRegistry,
+  port: u16,
+  /// 实时转码使用的编码器, 第一次转码时检测显卡后确定
+  encoder: tokio::sync::OnceCell<EncoderPreset>,
+}
+
+impl AppState {
+  /// 流的本地地址, 供 ffmpeg 读取明文 <br>
+  /// 地址中带的是新签发的内部凭据, 需要在 ffmpeg 会话结束时释放返回的 StreamCredential
+  fn internal_stream_url(&self, token: &str) -> (String, StreamCredential) {
+    let value = random_token();
+    self.credentials.lock().unwrap().insert(value.clone(), token.to_string());
+    let url = format!(
+      "http://127.0.0.1:{}/stream/{}?{}={}",
+      self.port, token, ACCESS_TOKEN_PARAM, value
+    );
+    (url, StreamCredential { value, registry: self.credentials.clone() })
+  }
 
-  let app = Router::new().route("/video", get(video_handler)).with_state(state);
+  /// 内部凭据只能访问签发时指定的流的 /stream 地址
+  fn credential_allows(&self, credential: &str, path: &str) -> bool {
+    let credentials = self.credentials.lock().unwrap();
+    credentials.get(credential).is_some_and(|token| path.strip_prefix("/stream/") == Some(token.as_str()))
+  }
+}
 
+/// 启动内部流媒体服务器, 每个注册的流挂载在 /stream/{token}, HLS 播放列表为 /hls/{token}/index.m3u8,
+/// 实时转码为 /transcode/{token}?t= <br>
+/// shutdown_rx: 这是一个“遥控器”。当你的主程序（比如 Tauri 窗口关闭时）发送信号，这个服务器就会优雅退出，停止占用资源。
+pub async fn start_server(
+  app: AppHandle,
+  streams: StreamRegistry,
+  config: Arc<ServerConfig>,
+  shutdown_rx: oneshot::Receiver<()>,
+) -> Result<u16, String> {
   // 端口写 0 是一个系统约定，意思是“操作系统你帮我随便分一个没人在用的端口”
   let addr = "127.0.0.1:0";
-  let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+  let listener = tokio::net::TcpListener::bind(addr).await.map_err(|e| e.to_string())?;
+
+  let port = listener.local_addr().map_err(|e| e.to_string())?.port();
+  clear_hls_cache(&app);
+  let state =
+    Arc::new(AppState { app, streams, config, credentials: Default::default(), port, encoder: Default::default() });
+
+  let app = Router::new()
+    .route("/stream/{token}", get(stream_handler))
+    .route("/hls/{token}/{file}", get(hls_handler))
+    .route("/transcode/{token}", get(transcode_handler))
+    .layer(middleware::from_fn_with_state(state.clone(), track_stats))
+    .layer(middleware::from_fn_with_state(state.clone(), access_guard))
+    .with_state(state.clone());
+
+  spawn_stats_reporter(Arc::downgrade(&state));
 
-  let port = listener.local_addr().unwrap().port();
   log::info!("Internal streaming server running on http://127.0.0.1:{}", port);
 
   // 使用 with_graceful_shutdown 监听关闭信号
@@ -51,120 +213,675 @@ pub async fn start_server(password: String, video_path: PathBuf, shutdown_rx: on
       .unwrap();
   });
 
-  port
+  Ok(port)
+}
+
+/// 访问控制: 校验 Host (防 DNS 重绑定)、可选的 Origin 以及会话访问令牌 (或 ffmpeg 使用的内部凭据)
+async fn access_guard(State(state): State<Arc<AppState>>, req: Request, next: Next) -> Response {
+  let headers = req.headers();
+
+  // DNS 重绑定的请求 Host 是攻击者的域名
+  let host = headers.get(header::HOST).and_then(|v| v.to_str().ok()).unwrap_or_default();
+  let allowed_hosts = [format!("127.0.0.1:{}", state.port), format!("localhost:{}", state.port)];
+  if !allowed_hosts.iter().any(|h| h == host) {
+    return (StatusCode::FORBIDDEN, "Host not allowed").into_response();
+  }
+
+  if state.config.check_origin.load(Ordering::Relaxed) {
+    if let Some(origin) = headers.get(header::ORIGIN) {
+      if !ALLOWED_ORIGINS.iter().any(|o| origin.as_bytes() == o.as_bytes()) {
+        return (StatusCode::FORBIDDEN, "Origin not allowed").into_response();
+      }
+    }
+  }
+
+  let provided = request_access_token(&req).unwrap_or_default();
+  if !bool::from(provided.as_bytes().ct_eq(state.config.access_token.as_bytes()))
+    && !state.credential_allows(&provided, req.uri().path())
+  {
+    return (StatusCode::UNAUTHORIZED, "Invalid access token").into_response();
+  }
+
+  next.run(req).await
+}
+
+/// 访问日志和统计: 按路径中的令牌找到流, 记录请求数、活动连接并统计响应体的字节数
+async fn track_stats(State(state): State<Arc<AppState>>, req: Request, next: Next) -> Response {
+  // 路径格式为 /stream/{token}、/hls/{token}/{file} 或 /transcode/{token}
+  let token = req.uri().path().split('/').nth(2).unwrap_or_default();
+  let Some(entry) = state.streams.read().unwrap().get(token).cloned() else {
+    return next.run(req).await;
+  };
+
+  let connection = entry.stats.begin_request();
+  let method = req.method().clone();
+  let path = req.uri().path().to_string();
+  let range = req.headers().get(header::RANGE).and_then(|v| v.to_str().ok()).unwrap_or("-").to_string();
+
+  let response = next.run(req).await;
+  log::debug!("{} {} range={} -> {}", method, path, range, response.status());
+
+  // 连接守卫随响应体一起释放, 客户端断开时也会减少活动连接数; 流关闭时响应体立即结束
+  let (parts, body) = response.into_parts();
+  let body = body
+    .into_data_stream()
+    .map(move |chunk| {
+      if let Ok(data) = &chunk {
+        connection.add_bytes(data.len() as u64);
+      }
+      chunk
+    })
+    .take_until(entry.closed.clone().cancelled_owned());
+  Response::from_parts(parts, Body::from_stream(body))
+}
+
+/// 定时向前端发送 stream_stats 事件, 附带每个流的发送速率, 服务器关闭后自动退出
+fn spawn_stats_reporter(state: std::sync::Weak<AppState>) {
+  async_runtime::spawn(async move {
+    let mut last_bytes: HashMap<String, u64> = HashMap::new();
+    loop {
+      tokio::time::sleep(STATS_INTERVAL).await;
+      let Some(state) = state.upgrade() else {
+        break;
+      };
+
+      let stats: Vec<StreamStatsSnapshot> = state
+        .streams
+        .read()
+        .unwrap()
+        .iter()
+        .map(|(token, entry)| {
+          let mut snapshot = entry.stats.snapshot(token);
+          let previous = last_bytes.get(token).copied().unwrap_or(0);
+          snapshot.bytes_per_second =
+            snapshot.bytes_sent.saturating_sub(previous) * 1000 / STATS_INTERVAL.as_millis() as u64;
+          snapshot
+        })
+        .collect();
+
+      last_bytes = stats.iter().map(|s| (s.token.clone(), s.bytes_sent)).collect();
+      if !stats.is_empty() {
+        let _ = state.app.emit("stream_stats", stats);
+      }
+    }
+  });
+}
+
+/// 从查询参数或请求头中取出访问令牌
+fn request_access_token(req: &Request) -> Option<String> {
+  let from_query = req.uri().query().and_then(|query| {
+    query.split('&').find_map(|pair| match pair.split_once('=') {
+      Some((ACCESS_TOKEN_PARAM, value)) => Some(value.to_string()),
+      _ => None,
+    })
+  });
+
+  from_query.or_else(|| {
+    let headers = req.headers();
+    headers.get(ACCESS_TOKEN_HEADER).and_then(|v| v.to_str().ok()).map(str::to_string).or_else(|| {
+      let auth = headers.get(header::AUTHORIZATION).and_then(|v| v.to_str().ok())?;
+      auth.strip_prefix("Bearer ").map(str::to_string)
+    })
+  })
+}
+
+/// 视频请求的总入口。当播放器请求 /stream/{token} 时，进入此函数
+async fn stream_handler(
+  State(state): State<Arc<AppState>>,
+  Path(token): Path<String>,
+  req: Request,
+) -> impl IntoResponse {
+  let Some(entry) = state.streams.read().unwrap().get(&token).cloned() else {
+    return (StatusCode::NOT_FOUND, "Stream not found").into_response();
+  };
+  stream_response(&entry, req.headers()).await
 }
 
-/// 视频请求的总入口。当播放器请求 /video 时，进入此函数
-async fn video_handler(State(state): State<Arc<AppState>>, req: Request) -> impl IntoResponse {
-  let mut file = match File::open(&state.video_path).await {
+/// 按请求头 (Range、If-Range) 生成流的响应: 完整内容、单个区间、多个区间或 416
+async fn stream_response(entry: &StreamEntry, req_headers: &HeaderMap) -> Response {
+  let file = match File::open(&entry.path).await {
     Ok(file) => file,
     Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to open file: {}", e)).into_response(),
   };
 
-  let mut salt = [0u8; SALT_LEN];
-  if let Err(e) = file.read_exact(&mut salt).await {
-    return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to read salt: {}", e)).into_response();
-  }
+  // 密钥在注册时已经派生好, 拖动进度条产生的大量 Range 请求不需要重复运行 Argon2
+  let cipher = entry.cipher.clone();
+  let video_data_size = entry.size;
 
-  let key = derive_key(&state.password, &salt);
-  let metadata = file.metadata().await.unwrap();
-  let total_file_size = metadata.len();
-  let video_data_size = total_file_size - SALT_LEN as u64;
+  // 用文件的修改时间和明文大小作为校验值, 供 If-Range 判断文件是否变化
+  let modified = file.metadata().await.ok().and_then(|m| m.modified().ok());
+  let etag = format!(
+    "\"{:x}-{:x}\"",
+    video_data_size,
+    modified.and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map(|d| d.as_nanos()).unwrap_or(0)
+  );
+  let last_modified = modified.map(httpdate::fmt_http_date);
+
+  let mut headers = HeaderMap::new();
+  match HeaderValue::from_str(&entry.content_type) {
+    Ok(content_type) => headers.insert(header::CONTENT_TYPE, content_type),
+    Err(_) => headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(FALLBACK_CONTENT_TYPE)),
+  };
+  headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+  headers.insert(header::ETAG, HeaderValue::from_str(&etag).unwrap());
+  if let Some(last_modified) = &last_modified {
+    headers.insert(header::LAST_MODIFIED, HeaderValue::from_str(last_modified).unwrap());
+  }
 
   // 视频播放器通常不会一次请求整个文件，而是发送 Range: bytes=0-1024 这样的头，以此实现“拖动进度条”和“分段缓冲”。
-  let range = req.headers().get(header::RANGE).and_then(|v| v.to_str().ok());
+  // If-Range 不匹配时说明客户端缓存的是旧文件, 忽略 Range 返回完整内容
+  let range = req_headers
+    .get(header::RANGE)
+    .and_then(|v| v.to_str().ok())
+    .filter(|_| if_range_matches(req_headers, &etag, last_modified.as_deref()))
+    .map(|v| parse_range(v, video_data_size))
+    .unwrap_or(RangeRequest::Full);
 
-  if let Some(range_str) = range {
-    // 如果有 Range 头，进入 handle_range_request（绝大多数情况）
-    handle_range_request(file, video_data_size, range_str, key).await
-  } else {
-    // 否则直接流式传输整个文件
-    let mut current_offset = 0u64;
-    let stream = ReaderStream::new(file).map(move |chunk: Result<Bytes, std::io::Error>| match chunk {
-      Ok(bytes) => {
-        let mut data = BytesMut::from(&bytes[..]);
-        encrypt_decrypt_at_offset(&mut data, current_offset, &key);
-        current_offset += data.len() as u64;
-        Ok::<_, std::io::Error>(data.freeze())
+  match range {
+    RangeRequest::Full => {
+      // 直接流式传输整个文件
+      let stream = decrypt_stream(file, cipher, 0, video_data_size).await;
+      headers.insert(header::CONTENT_LENGTH, HeaderValue::from(video_data_size));
+
+      (StatusCode::OK, headers, Body::from_stream(stream)).into_response()
+    }
+    RangeRequest::Unsatisfiable => {
+      headers.insert(
+        header::CONTENT_RANGE,
+        HeaderValue::from_str(&format!("bytes */{}", video_data_size)).unwrap(),
+      );
+      (StatusCode::RANGE_NOT_SATISFIABLE, headers, "Range Not Satisfiable").into_response()
+    }
+    RangeRequest::Ranges(ranges) if ranges.len() == 1 => {
+      // 绝大多数情况: 单个区间
+      let (start, end) = ranges[0];
+      handle_range_request(file, video_data_size, start, end, cipher, headers).await
+    }
+    RangeRequest::Ranges(ranges) => {
+      drop(file);
+      handle_multi_range_request(entry, video_data_size, ranges, cipher, headers).await
+    }
+  }
+}
+
+/// HLS 请求入口: 第一次请求时启动 ffmpeg 切片, 之后从缓存目录读取播放列表和分段
+async fn hls_handler(State(state): State<Arc<AppState>>, Path((token, file)): Path<(String, String)>) -> Response {
+  let Some(entry) = state.streams.read().unwrap().get(&token).cloned() else {
+    return (StatusCode::NOT_FOUND, "Stream not found").into_response();
+  };
+  if !is_hls_file_name(&file) {
+    return (StatusCode::NOT_FOUND, "Not found").into_response();
+  }
+
+  let dir = {
+    let mut hls = entry.hls.lock().unwrap();
+    match hls.as_ref() {
+      Some(session) => session.dir().to_path_buf(),
+      None => {
+        let dir = match hls_root_dir(&state.app) {
+          Ok(root) => root.join(&token),
+          Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
+        };
+        let (input_url, credential) = state.internal_stream_url(&token);
+        match HlsSession::start(&state.app, &input_url, credential, dir) {
+          Ok(session) => hls.insert(session).dir().to_path_buf(),
+          Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
+        }
       }
-      Err(e) => Err(e),
-    });
+    }
+  };
 
-    let mut headers = HeaderMap::new();
-    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("video/mp4"));
-    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
-    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(video_data_size));
+  // ffmpeg 是异步切片的, 文件可能还没生成, 轮询等待
+  let path = dir.join(&file);
+  let deadline = Instant::now() + HLS_WAIT_TIMEOUT;
+  let content = loop {
+    match tokio::fs::read(&path).await {
+      // 播放列表至少要有一个分段才能开始播放
+      Ok(content) if file != PLAYLIST_NAME || content.windows(7).any(|w| w == b"#EXTINF") => break content,
+      _ if Instant::now() >= deadline => return (StatusCode::GATEWAY_TIMEOUT, "HLS segment not ready").into_response(),
+      _ => tokio::time::sleep(Duration::from_millis(200)).await,
+    }
+  };
+
+  if file == PLAYLIST_NAME {
+    // 分段地址是相对路径, 不会带上查询参数, 需要逐行补上访问令牌
+    let playlist = String::from_utf8_lossy(&content)
+      .lines()
+      .map(|line| match line.starts_with('#') || line.is_empty() {
+        true => line.to_string(),
+        false => format!("{}?{}={}", line, ACCESS_TOKEN_PARAM, state.config.access_token),
+      })
+      .collect::<Vec<_>>()
+      .join("\n");
 
-    (StatusCode::OK, headers, Body::from_stream(stream)).into_response()
+    let headers = [(header::CONTENT_TYPE, "application/vnd.apple.mpegurl"), (header::CACHE_CONTROL, "no-cache")];
+    (StatusCode::OK, headers, playlist).into_response()
+  } else {
+    ([(header::CONTENT_TYPE, "video/mp2t")], content).into_response()
   }
 }
 
+/// /transcode 的查询参数
+#[derive(Deserialize)]
+struct TranscodeParams {
+  /// 开始时间 (秒)
+  t: Option<f64>,
+}
+
+/// 实时转码入口: 从 ?t= 指定的时间开始输出 H.264/AAC 的 fragmented MP4 <br>
+/// 输出长度未知, 不支持 Range, 拖动进度条时需要带上新的 t 重新请求
+async fn transcode_handler(
+  State(state): State<Arc<AppState>>,
+  Path(token): Path<String>,
+  Query(params): Query<TranscodeParams>,
+) -> Response {
+  if !state.streams.read().unwrap().contains_key(&token) {
+    return (StatusCode::NOT_FOUND, "Stream not found").into_response();
+  }
+
+  let start = params.t.unwrap_or(0.0);
+  if !start.is_finite() || start < 0.0 {
+    return (StatusCode::BAD_REQUEST, "Invalid start time").into_response();
+  }
+
+  // 没有检测到显卡时使用 CPU 编码
+  let encoder =
+    state.encoder.get_or_init(|| async { select_best_encoder(&get_gpu_info().await.unwrap_or_default()) }).await;
+
+  let (input_url, credential) = state.internal_stream_url(&token);
+  match transcode_stream(&state.app, &input_url, credential, start, encoder) {
+    Ok(stream) => {
+      let headers = [(header::CONTENT_TYPE, "video/mp4"), (header::ACCEPT_RANGES, "none")];
+      (StatusCode::OK, headers, Body::from_stream(stream)).into_response()
+    }
+    Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
+  }
+}
+
+/// 读取文件头并派生密钥 (只在注册流时调用一次), 没有秘密值时按普通文件处理 <br>
+/// return: (解密方式, 明文大小)
+async fn open_cipher(file: &mut File, secret: Option<&[u8]>) -> Result<(Cipher, u64), CryptoError> {
+  let Some(secret) = secret else {
+    let size = file.metadata().await.map_err(|e| e.to_string())?.len();
+    return Ok((Cipher::Plain, size));
+  };
+
+  let header = EncryptedHeader::read_from(file).await?;
+  let key = Zeroizing::new(header.unlock(secret).await?);
+  let size = header.plain_size();
+
+  let cipher = match header {
+    EncryptedHeader::Container(header) => Cipher::Container { header: Arc::new(header), key },
+    EncryptedHeader::Legacy { .. } => Cipher::Legacy { key },
+  };
+  Ok((cipher, size))
+}
+
+/// 确定流的 Content-Type <br>
+/// 优先使用文件头元数据中记录的原始 MIME 类型, 没有时解密开头的数据按魔数识别, 支持视频、音频和图片
+async fn detect_content_type(file: File, cipher: &Cipher, size: u64) -> Result<String, CryptoError> {
+  if let Cipher::Container { header, key } = cipher {
+    if let Some(mime) = header.metadata(key)?.and_then(|m| m.mime) {
+      return Ok(mime);
+    }
+  }
+
+  let mut stream = decrypt_stream(file, cipher.clone(), 0, size.min(SNIFF_LEN)).await;
+  let mut head = Vec::new();
+  while let Some(chunk) = stream.next().await {
+    head.extend_from_slice(&chunk.map_err(|e| e.to_string())?);
+  }
+
+  Ok(infer::get(&head).map(|t| t.mime_type().to_string()).unwrap_or_else(|| FALLBACK_CONTENT_TYPE.to_string()))
+}
+
 /// 1. 用户在播放器拖动进度条到 50%。
-/// 2. 播放器发送 HTTP 请求：GET /video, Range: bytes=50000-。
-/// 3. Axum 收到请求，调用 video_handler。
-/// 4. Rust 代码计算：物理文件需要跳过 50000 + SALT_LEN 字节。
+/// 2. 播放器发送 HTTP 请求：GET /stream/{token}, Range: bytes=50000-。
+/// 3. Axum 收到请求，调用 stream_handler。
+/// 4. Rust 代码计算：逻辑位置 50000 落在哪个加密分块（旧格式则是物理文件跳过 50000 + SALT_LEN 字节）。
 /// 5. Tokio file.seek(...) 跳到物理位置。
-/// 6. ReaderStream 读取一块 64KB 的加密数据。
-/// 7. Map 闭包 拿着密码和 offset 对这 64KB 进行解密。
+/// 6. 读取一块 64KB 的加密数据。
+/// 7. 拿着密钥和 offset 对这 64KB 进行解密（容器格式还会校验认证标签）。
 /// 8. Axum 将解密后的 64KB 发回给播放器。
-/// 9. 播放器 以为自己在看普通 MP4，实际上是在看实时解密流。
-async fn handle_range_request(mut file: File, video_size: u64, range_str: &str, key: [u8; 32]) -> Response {
-  // 1. 解析请求范围
-  let range_value = range_str.replace("bytes=", "");
-  let parts: Vec<&str> = range_value.split('-').collect();
-  // ...解析出 start 和 end ...
-  let start = parts[0].parse::<u64>().unwrap_or(0);
-  let end = parts.get(1).and_then(|s| s.parse::<u64>().ok()).filter(|&e| e < video_size).unwrap_or(video_size - 1);
+/// 9. 播放器 以为自己在看普通媒体文件，实际上是在看实时解密流。
+async fn handle_range_request(
+  file: File,
+  video_size: u64,
+  start: u64,
+  end: u64,
+  cipher: Cipher,
+  mut headers: HeaderMap,
+) -> Response {
+  let range_len = end - start + 1;
+  let stream = decrypt_stream(file, cipher, start, range_len).await;
+
+  headers.insert(
+    header::CONTENT_RANGE,
+    HeaderValue::from_str(&format!("bytes {}-{}/{}", start, end, video_size)).unwrap(),
+  );
+  headers.insert(header::CONTENT_LENGTH, HeaderValue::from(range_len));
+
+  (StatusCode::PARTIAL_CONTENT, headers, Body::from_stream(stream)).into_response()
+}
+
+/// 多个区间: 返回 multipart/byteranges, 每个区间一个部分, 各自带 Content-Type 和 Content-Range
+async fn handle_multi_range_request(
+  entry: &StreamEntry,
+  video_size: u64,
+  ranges: Vec<(u64, u64)>,
+  cipher: Cipher,
+  mut headers: HeaderMap,
+) -> Response {
+  let boundary = random_token();
 
-  if start >= video_size {
-    return (StatusCode::RANGE_NOT_SATISFIABLE, "Range Not Satisfiable").into_response();
+  let mut parts = Vec::with_capacity(ranges.len());
+  let mut content_length = 0u64;
+  for (i, (start, end)) in ranges.into_iter().enumerate() {
+    let file = match File::open(&entry.path).await {
+      Ok(file) => file,
+      Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to open file: {}", e)).into_response(),
+    };
+
+    let part_header = format!(
+      "{}--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+      if i == 0 { "" } else { "\r\n" },
+      boundary,
+      entry.content_type,
+      start,
+      end,
+      video_size
+    );
+    content_length += part_header.len() as u64 + (end - start + 1);
+
+    let part_header = stream::once(async move { Ok::<_, std::io::Error>(Bytes::from(part_header)) });
+    parts.push(part_header.chain(decrypt_stream(file, cipher.clone(), start, end - start + 1).await).boxed());
   }
 
-  let range_len = end - start + 1;
+  let closing = format!("\r\n--{}--\r\n", boundary);
+  content_length += closing.len() as u64;
+  parts.push(stream::once(async move { Ok(Bytes::from(closing)) }).boxed());
+
+  headers.insert(
+    header::CONTENT_TYPE,
+    HeaderValue::from_str(&format!("multipart/byteranges; boundary={}", boundary)).unwrap(),
+  );
+  headers.insert(header::CONTENT_LENGTH, HeaderValue::from(content_length));
+
+  (
+    StatusCode::PARTIAL_CONTENT,
+    headers,
+    Body::from_stream(stream::iter(parts).flatten()),
+  )
+    .into_response()
+}
+
+/// 生成明文区间 [start, start + len) 的解密流
+async fn decrypt_stream(
+  file: File,
+  cipher: Cipher,
+  start: u64,
+  len: u64,
+) -> BoxStream<'static, Result<Bytes, std::io::Error>> {
+  match cipher {
+    Cipher::Container { header, key } => container_stream(file, header, key, start, len),
+    Cipher::Legacy { key } => legacy_stream(file, key, start, len).await,
+    Cipher::Plain => plain_stream(file, start, len).await,
+  }
+}
+
+/// 容器格式: 找到 start 所在的分块, 逐块读取、校验并解密, 再裁剪出请求的区间
+fn container_stream(
+  file: File,
+  header: Arc<ContainerHeader>,
+  key: Zeroizing<[u8; KEY_LEN]>,
+  start: u64,
+  len: u64,
+) -> BoxStream<'static, Result<Bytes, std::io::Error>> {
+  let end = start + len;
+  let chunk_size = header.chunk_size as u64;
+
+  stream::try_unfold((file, start), move |(mut file, pos)| {
+    let header = header.clone();
+    let key = key.clone();
+    async move {
+      if pos >= end {
+        return Ok(None);
+      }
+
+      let index = pos / chunk_size;
+      let chunk_start = index * chunk_size;
+
+      file.seek(SeekFrom::Start(header.chunk_offset(index))).await?;
+      let mut buffer = vec![0u8; header.chunk_plain_len(index) + TAG_LEN];
+      file.read_exact(&mut buffer).await?;
+
+      open_chunk(&key, &header.nonce, index, header.as_bytes(), &mut buffer)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+
+      // 只发送请求区间内的部分
+      let from = (pos - chunk_start) as usize;
+      let to = std::cmp::min(buffer.len() as u64, end - chunk_start) as usize;
+      let data = Bytes::copy_from_slice(&buffer[from..to]);
+
+      Ok(Some((data, (file, chunk_start + to as u64))))
+    }
+  })
+  .boxed()
+}
+
+/// 旧格式: 直接 seek 到物理位置, 按偏移生成密钥流解密
+async fn legacy_stream(
+  mut file: File,
+  key: Zeroizing<[u8; KEY_LEN]>,
+  start: u64,
+  len: u64,
+) -> BoxStream<'static, Result<Bytes, std::io::Error>> {
   // 2. 文件定位（这是关键点！）
   // 物理文件的 offset = 逻辑请求的 start + 头部盐的长度
-  file.seek(SeekFrom::Start(start + SALT_LEN as u64)).await.unwrap();
+  if let Err(e) = file.seek(SeekFrom::Start(start + SALT_LEN as u64)).await {
+    return stream::once(async move { Err(e) }).boxed();
+  }
 
   // 流加密算法（如 CTR 模式或 XOR）通常依赖数据在文件中的位置。第 100 个字节的解密方式和第 200 个字节不同。所以代码里维护了一个 current_offset。
   let mut current_offset = start;
-  let mut remaining = range_len;
 
   // 3. 创建加密流
-  // ReaderStream 把文件变成了水流，一块一块地流出来
+  // ReaderStream 把文件变成了水流，一块一块地流出来; take(len) 保证读到区间末尾就结束, 不会继续读到文件结尾
   // .map() 就像在这个水管上装了一个滤网。每一块数据流过时，都会经过 encrypt_decrypt_at_offset 处理。
   // 处理完的数据直接发给 HTTP 响应，内存中只有这 64KB 的明文，非常安全且节省内存。
-  let stream =
-    ReaderStream::with_capacity(file, 64 * 1024).map(move |chunk: Result<Bytes, std::io::Error>| match chunk {
-      Ok(bytes) => {
-        // ... 截取需要的长度 ...
-        let take_len = std::cmp::min(bytes.len() as u64, remaining) as usize;
-        if take_len == 0 {
-          return Ok::<_, std::io::Error>(Bytes::new());
-        }
-        let mut data = BytesMut::from(&bytes[..take_len]);
+  ReaderStream::with_capacity(file.take(len), 64 * 1024)
+    .map(move |chunk: Result<Bytes, std::io::Error>| {
+      let mut data = BytesMut::from(&chunk?[..]);
+
+      // 4. 实时解密！
+      // 拿到这一块密文数据，根据当前的 offset 进行解密
+      encrypt_decrypt_at_offset(&mut data, current_offset, &key, LEGACY_NONCE);
+
+      // 更新 offset，准备解密下一块
+      current_offset += data.len() as u64;
+
+      // 发送给浏览器
+      Ok::<_, std::io::Error>(data.freeze())
+    })
+    .boxed()
+}
+
+/// 普通文件: seek 到 start 后原样读取 len 字节
+async fn plain_stream(mut file: File, start: u64, len: u64) -> BoxStream<'static, Result<Bytes, std::io::Error>> {
+  if let Err(e) = file.seek(SeekFrom::Start(start)).await {
+    return stream::once(async move { Err(e) }).boxed();
+  }
 
-        // 4. 实时解密！
-        // 拿到这一块密文数据，根据当前的 offset 进行解密
-        encrypt_decrypt_at_offset(&mut data, current_offset, &key);
+  ReaderStream::with_capacity(file.take(len), 64 * 1024).boxed()
+}
 
-        // 更新 offset，准备解密下一块
-        current_offset += take_len as u64;
-        remaining -= take_len as u64;
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::utils::{
+    container::EncryptWriter,
+    crypto::{derive_key, generate_nonce_prefix, generate_salt, random_file_name, KdfParams},
+  };
 
-        // 发送给浏览器
-        Ok::<_, std::io::Error>(data.freeze())
+  /// 旧格式文件: 盐值 + ChaCha20 流密文
+  fn write_legacy(data: &[u8], key: &[u8; KEY_LEN]) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("rigel_legacy_{}.enc", random_file_name()));
+    let mut encrypted = data.to_vec();
+    encrypt_decrypt_at_offset(&mut encrypted, 0, key, LEGACY_NONCE);
+    std::fs::write(&path, [vec![0u8; SALT_LEN], encrypted].concat()).unwrap();
+    path
+  }
+
+  #[tokio::test]
+  async fn legacy_stream_stops_at_range_end() {
+    let key = [7u8; KEY_LEN];
+    let data: Vec<u8> = (0..300_000u32).map(|i| (i * 31 % 251) as u8).collect();
+    let path = write_legacy(&data, &key);
+
+    for (start, len) in [(0, 10), (1000, 100_000), (299_990, 10), (0, data.len() as u64)] {
+      let file = File::open(&path).await.unwrap();
+      let mut stream = decrypt_stream(file, Cipher::Legacy { key: Zeroizing::new(key) }, start, len).await;
+      let mut out = Vec::new();
+      let mut chunks = 0;
+      while let Some(chunk) = stream.next().await {
+        out.extend_from_slice(&chunk.unwrap());
+        chunks += 1;
       }
-      Err(e) => Err(e),
-    });
 
-  let mut headers = HeaderMap::new();
-  headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("video/mp4"));
-  headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
-  headers.insert(
-    header::CONTENT_RANGE,
-    HeaderValue::from_str(&format!("bytes {}-{}/{}", start, end, video_size)).unwrap(),
-  );
-  headers.insert(header::CONTENT_LENGTH, HeaderValue::from(range_len));
+      assert_eq!(out, data[start as usize..(start + len) as usize], "start {} len {}", start, len);
+      // 区间结束后不能继续读到文件末尾
+      assert!(
+        chunks <= len.div_ceil(64 * 1024) + 1,
+        "start {} len {}: {} chunks",
+        start,
+        len,
+        chunks
+      );
+    }
+    std::fs::remove_file(&path).unwrap();
+  }
 
-  (StatusCode::PARTIAL_CONTENT, headers, Body::from_stream(stream)).into_response()
+  /// 明文文件注册成的流, 用于测试 Range 响应
+  async fn plain_entry(data: &[u8]) -> StreamEntry {
+    let path = std::env::temp_dir().join(format!("rigel_plain_{}.bin", random_file_name()));
+    std::fs::write(&path, data).unwrap();
+    StreamEntry::open(path, None).await.unwrap()
+  }
+
+  async fn get_with_range(entry: &StreamEntry, range: &str) -> (StatusCode, HeaderMap, Bytes) {
+    let mut headers = HeaderMap::new();
+    headers.insert(header::RANGE, HeaderValue::from_str(range).unwrap());
+    let (parts, body) = stream_response(entry, &headers).await.into_parts();
+    (
+      parts.status,
+      parts.headers,
+      axum::body::to_bytes(body, usize::MAX).await.unwrap(),
+    )
+  }
+
+  #[tokio::test]
+  async fn unsatisfiable_range_returns_416_with_content_range() {
+    let data = vec![0x42u8; 1000];
+    let entry = plain_entry(&data).await;
+
+    for range in ["bytes=1000-", "bytes=2000-3000", "bytes=1000-1000,1500-"] {
+      let (status, headers, _) = get_with_range(&entry, range).await;
+      assert_eq!(status, StatusCode::RANGE_NOT_SATISFIABLE, "{}", range);
+      assert_eq!(headers[header::CONTENT_RANGE], "bytes */1000", "{}", range);
+    }
+    std::fs::remove_file(&entry.path).unwrap();
+  }
+
+  #[tokio::test]
+  async fn multiple_ranges_return_multipart_byteranges() {
+    let data: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+    let entry = plain_entry(&data).await;
+
+    let (status, headers, body) = get_with_range(&entry, "bytes=0-9,100-119,-5").await;
+    assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+    let content_type = headers[header::CONTENT_TYPE].to_str().unwrap();
+    let boundary = content_type.strip_prefix("multipart/byteranges; boundary=").unwrap();
+    assert!(!boundary.is_empty());
+
+    let mut expected = Vec::new();
+    for (i, (start, end)) in [(0, 9), (100, 119), (995, 999)].into_iter().enumerate() {
+      if i > 0 {
+        expected.extend_from_slice(b"\r\n");
+      }
+      expected.extend_from_slice(
+        format!(
+          "--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/1000\r\n\r\n",
+          boundary, entry.content_type, start, end
+        )
+        .as_bytes(),
+      );
+      expected.extend_from_slice(&data[start..=end]);
+    }
+    expected.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+
+    assert_eq!(body, expected);
+    assert_eq!(headers[header::CONTENT_LENGTH], body.len().to_string().as_str());
+    std::fs::remove_file(&entry.path).unwrap();
+  }
+
+  async fn read_range(file: File, cipher: Cipher, start: u64, len: u64) -> usize {
+    let mut stream = decrypt_stream(file, cipher, start, len).await;
+    let mut read = 0;
+    while let Some(chunk) = stream.next().await {
+      read += chunk.unwrap().len();
+    }
+    read
+  }
+
+  /// 对比每个 Range 请求都重新派生密钥和使用注册时缓存的密钥, 在随机位置读取 64 KiB 的耗时 <br>
+  /// cargo test --release decrypt_stream_key_cache -- --ignored --nocapture
+  #[tokio::test]
+  #[ignore]
+  async fn decrypt_stream_key_cache_benchmark() {
+    const SECRET: &[u8] = b"benchmark password";
+    const REQUESTS: u32 = 20;
+    const READ_LEN: u64 = 64 * 1024;
+
+    // 默认 (balanced) KDF 参数, 与实际加密的文件一致
+    let path = std::env::temp_dir().join(format!("rigel_bench_{}.enc", random_file_name()));
+    let data = vec![0x5au8; 16 * 1024 * 1024];
+    let kdf = KdfParams::default();
+    let salt = generate_salt();
+    let key = derive_key(SECRET, &salt, &kdf).unwrap();
+    let header = ContainerHeader::new(kdf, salt, generate_nonce_prefix(), data.len() as u64, &key);
+    let mut writer = EncryptWriter::create(File::create(&path).await.unwrap(), header, key).await.unwrap();
+    writer.write(&data).await.unwrap();
+    writer.finish().await.unwrap();
+
+    let size = data.len() as u64;
+    let offsets: Vec<u64> = (0..REQUESTS).map(|_| rand::random_range(0..size - READ_LEN)).collect();
+
+    // 每个请求: 读取文件头、运行 Argon2、解密区间
+    let started = Instant::now();
+    for &offset in &offsets {
+      let mut file = File::open(&path).await.unwrap();
+      let (cipher, _) = open_cipher(&mut file, Some(SECRET)).await.unwrap();
+      assert_eq!(read_range(file, cipher, offset, READ_LEN).await, READ_LEN as usize);
+    }
+    let derive_each = started.elapsed() / REQUESTS;
+
+    // 注册时派生一次, 之后的请求直接使用缓存的密钥
+    let started = Instant::now();
+    let entry = StreamEntry::open(path.clone(), Some(SECRET)).await.unwrap();
+    let open = started.elapsed();
+    let started = Instant::now();
+    for &offset in &offsets {
+      let file = File::open(&path).await.unwrap();
+      assert_eq!(
+        read_range(file, entry.cipher.clone(), offset, READ_LEN).await,
+        READ_LEN as usize
+      );
+    }
+    let cached = started.elapsed() / REQUESTS;
+
+    println!("per-request key derivation: {:?}/request", derive_each);
+    println!("cached key: {:?}/request (StreamEntry::open {:?})", cached, open);
+    std::fs::remove_file(&path).unwrap();
+    assert!(cached < derive_each);
+  }
 }
@@ -0,0 +1,97 @@
+// 流媒体服务器的访问统计: 每个流一份计数器, 由服务器中间件更新, 命令和定时事件读取
+use std::{
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+  },
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+/// 单个流的计数器, 全部使用原子变量, 请求处理时不需要加锁
+#[derive(Default)]
+pub struct StreamStats {
+  requests: AtomicU64,
+  bytes_sent: AtomicU64,
+  active_connections: AtomicU64,
+  /// 最后一次请求的时间 (Unix 毫秒), 0 表示还没有请求
+  last_access: AtomicU64,
+  /// 最后一次请求或发送数据的时间 (Unix 毫秒), 用于判断服务器是否空闲
+  last_activity: AtomicU64,
+}
+
+/// 统计快照, 返回给前端
+#[derive(Clone, Serialize)]
+pub struct StreamStatsSnapshot {
+  pub token: String,
+  pub requests: u64,
+  pub bytes_sent: u64,
+  pub active_connections: u64,
+  pub last_access: Option<u64>,
+  /// 最近一个统计周期的发送速率 (字节/秒), get_stream_stats 中始终为 0
+  pub bytes_per_second: u64,
+}
+
+impl StreamStats {
+  /// 记录一次新请求, 返回的守卫在响应发送完毕 (或连接断开) 时减少活动连接数
+  pub fn begin_request(self: &Arc<Self>) -> ActiveConnection {
+    self.requests.fetch_add(1, Ordering::Relaxed);
+    self.active_connections.fetch_add(1, Ordering::Relaxed);
+    let now = now_millis();
+    self.last_access.store(now, Ordering::Relaxed);
+    self.last_activity.store(now, Ordering::Relaxed);
+    ActiveConnection(self.clone())
+  }
+
+  pub fn bytes_sent(&self) -> u64 {
+    self.bytes_sent.load(Ordering::Relaxed)
+  }
+
+  pub fn active_connections(&self) -> u64 {
+    self.active_connections.load(Ordering::Relaxed)
+  }
+
+  /// 最后一次请求的时间 (Unix 毫秒), 没有请求时为 0
+  pub fn last_access(&self) -> u64 {
+    self.last_access.load(Ordering::Relaxed)
+  }
+
+  /// 最后一次请求或发送数据的时间 (Unix 毫秒) <br>
+  /// 暂停播放时连接通常不会断开, 但也不再传输数据, 不能按活动连接数判断是否空闲
+  pub fn last_activity(&self) -> u64 {
+    self.last_activity.load(Ordering::Relaxed)
+  }
+
+  pub fn snapshot(&self, token: &str) -> StreamStatsSnapshot {
+    let last_access = self.last_access.load(Ordering::Relaxed);
+    StreamStatsSnapshot {
+      token: token.to_string(),
+      requests: self.requests.load(Ordering::Relaxed),
+      bytes_sent: self.bytes_sent(),
+      active_connections: self.active_connections(),
+      last_access: (last_access > 0).then_some(last_access),
+      bytes_per_second: 0,
+    }
+  }
+}
+
+/// 活动连接守卫
+pub struct ActiveConnection(Arc<StreamStats>);
+
+impl ActiveConnection {
+  pub fn add_bytes(&self, len: u64) {
+    self.0.bytes_sent.fetch_add(len, Ordering::Relaxed);
+    self.0.last_activity.store(now_millis(), Ordering::Relaxed);
+  }
+}
+
+impl Drop for ActiveConnection {
+  fn drop(&mut self) {
+    self.0.active_connections.fetch_sub(1, Ordering::Relaxed);
+  }
+}
+
+pub fn now_millis() -> u64 {
+  SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or_default()
+}
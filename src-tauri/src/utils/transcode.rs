@@ -0,0 +1,84 @@
+// 实时转码: 用 ffmpeg sidecar 把 webview 无法解码的视频 (HEVC、MKV 等) 转成 H.264/AAC 的 fragmented MP4
+// 输出直接写到 stdout, 边转码边发送给播放器; 拖动进度条时播放器带上 ?t= 重新请求
+use bytes::Bytes;
+use futures_util::{stream, stream::BoxStream, StreamExt};
+use tauri::{async_runtime::Receiver, AppHandle};
+use tauri_plugin_shell::{
+  process::{CommandChild, CommandEvent},
+  ShellExt,
+};
+
+use crate::{shell::ffmpeg::EncoderPreset, utils::server::StreamCredential};
+
+/// 客户端断开 (响应流被丢弃) 时结束 ffmpeg 并吊销它的访问凭据
+struct ChildGuard {
+  child: Option<CommandChild>,
+  _credential: StreamCredential,
+}
+
+impl Drop for ChildGuard {
+  fn drop(&mut self) {
+    if let Some(child) = self.child.take() {
+      let _ = child.kill();
+    }
+  }
+}
+
+/// 从 start 秒开始转码 input_url, 返回 fragmented MP4 数据流 <br>
+/// credential 为 input_url 中的内部凭据, 随数据流一起释放
+pub(crate) fn transcode_stream(
+  app: &AppHandle,
+  input_url: &str,
+  credential: StreamCredential,
+  start: f64,
+  encoder: &EncoderPreset,
+) -> Result<BoxStream<'static, Result<Bytes, std::io::Error>>, String> {
+  let start = format!("{:.3}", start);
+
+  let mut args = vec![
+    "-hide_banner",
+    "-loglevel",
+    "error",
+    // -ss 放在 -i 之前是输入定位, ffmpeg 通过 Range 请求直接跳到目标位置附近, 不需要解码前面的内容
+    "-ss",
+    &start,
+    "-i",
+    input_url,
+    "-map",
+    "0:v:0?",
+    "-map",
+    "0:a:0?",
+  ];
+  args.extend(encoder.to_live_ffmpeg_args());
+  // empty_moov + frag_keyframe: 不需要回写文件头, 可以输出到管道
+  args.extend(["-movflags", "frag_keyframe+empty_moov+default_base_moof", "-f", "mp4", "pipe:1"]);
+
+  log::info!("ffmpeg {}", args.join(" "));
+  let (rx, child) = app
+    .shell()
+    .sidecar("ffmpeg")
+    .map_err(|e| format!("Failed to create sidecar: {}", e))?
+    .args(args)
+    // 默认按行切分 stdout, 二进制数据需要原样输出
+    .set_raw_out(true)
+    .spawn()
+    .map_err(|e| e.to_string())?;
+
+  let state: (Receiver<CommandEvent>, ChildGuard) = (rx, ChildGuard { child: Some(child), _credential: credential });
+  let stream = stream::unfold(state, |(mut rx, guard)| async move {
+    loop {
+      match rx.recv().await? {
+        CommandEvent::Stdout(data) => return Some((Ok(Bytes::from(data)), (rx, guard))),
+        CommandEvent::Stderr(line) => log::warn!("transcode ffmpeg: {}", String::from_utf8_lossy(&line).trim()),
+        CommandEvent::Error(e) => return Some((Err(std::io::Error::other(e)), (rx, guard))),
+        CommandEvent::Terminated(status) => {
+          log::info!("transcode ffmpeg exited with status {:?}", status.code);
+          return None;
+        }
+        _ => {}
+      }
+    }
+  });
+
+  Ok(stream.boxed())
+}
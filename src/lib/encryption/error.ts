@@ -0,0 +1,16 @@
+/**
+ * 加解密错误 (对应 Rust 端 CryptoError)
+ */
+export type CryptoError = {
+  kind: 'wrongPassword' | 'other';
+  message?: string;
+};
+
+/**
+ * 将后端返回的加解密错误转换为提示文字
+ */
+export function cryptoErrorMessage(e: unknown): string {
+  const err = e as CryptoError;
+  if (err?.kind === 'wrongPassword') return '密码错误';
+  return String(err?.message ?? e);
+}
@@ -0,0 +1,30 @@
+/**
+ * encrypt_progress 事件 (对应 Rust 端 JobProgressPayload)
+ */
+export type JobProgressPayload = {
+  job_id: number;
+  progress: number;
+};
+
+/**
+ * encrypt_error 事件 (对应 Rust 端 JobErrorPayload)
+ */
+export type JobErrorPayload = {
+  job_id: number;
+  message: string;
+};
+
+/**
+ * encrypt_complete / encrypt_cancelled 事件
+ */
+export type JobPayload = {
+  job_id: number;
+};
+
+/**
+ * encrypt_file 的返回值
+ */
+export type EncryptFileResult = {
+  job_id: number;
+  output_path: string;
+};
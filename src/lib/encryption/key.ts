@@ -0,0 +1,8 @@
+/**
+ * 密钥来源 (对应 Rust 端 KeySource), 可以直接传密码字符串
+ */
+export type KeySource =
+  | string
+  | { type: 'keyFile'; path: string }
+  | { type: 'passwordAndKeyFile'; password: string; keyFile: string }
+  | { type: 'keyring'; account: string };
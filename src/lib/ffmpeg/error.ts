@@ -0,0 +1,36 @@
+/**
+ * ffmpeg 错误 (对应 Rust 端 FfmpegError)
+ */
+export type FfmpegError =
+  | { kind: 'inputMissing'; path: string }
+  | { kind: 'probeFailed'; path: string; message: string }
+  | { kind: 'encoderUnavailable'; stderr: string[] }
+  | { kind: 'diskFull'; path: string }
+  | { kind: 'exited'; code: number | null; stderr: string[] }
+  | { kind: 'cancelled' }
+  | { kind: 'other'; message: string };
+
+/**
+ * 将后端返回的 ffmpeg 错误转换为提示文字
+ */
+export function ffmpegErrorMessage(e: unknown): string {
+  const err = e as FfmpegError;
+  switch (err?.kind) {
+    case 'inputMissing':
+      return `文件不存在: ${err.path}`;
+    case 'probeFailed':
+      return `无法读取视频信息: ${err.message}`;
+    case 'encoderUnavailable':
+      return `编码器不可用: ${err.stderr.at(-1) ?? ''}`;
+    case 'diskFull':
+      return `磁盘空间不足: ${err.path}`;
+    case 'exited':
+      return `ffmpeg 执行失败 code: ${err.code}, ${err.stderr.at(-1) ?? ''}`;
+    case 'cancelled':
+      return '已取消';
+    case 'other':
+      return err.message;
+    default:
+      return String(e);
+  }
+}
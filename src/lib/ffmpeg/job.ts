@@ -0,0 +1,61 @@
+import { invoke } from '@tauri-apps/api/core';
+import { listen } from '@tauri-apps/api/event';
+import type { FfmpegError } from './error';
+import type { FfmpegJobKind, FfmpegJobType, ProgressPayloadInterface } from './type';
+
+/**
+ * 将任务加入 ffmpeg 队列并等待结束, onProgress 只会收到本任务的 ffmpeg-progress 事件 <br>
+ * 任务失败或被取消时以 FfmpegError reject
+ */
+export async function runFfmpegJob(
+  kind: FfmpegJobKind,
+  onProgress: (payload: ProgressPayloadInterface) => void
+): Promise<void> {
+  let jobId: number | null = null;
+  // enqueue_ffmpeg_job 返回任务 ID 之前收到的事件, 拿到 ID 后再按 ID 过滤
+  const earlyProgress: ProgressPayloadInterface[] = [];
+  const earlyJobs: FfmpegJobType[] = [];
+
+  let settle: (job: FfmpegJobType) => void = () => {};
+  const finished = new Promise<FfmpegJobType>((resolve) => (settle = resolve));
+  const handleJob = (job: FfmpegJobType) => {
+    if (['completed', 'failed', 'cancelled'].includes(job.status)) {
+      settle(job);
+    }
+  };
+
+  const unlistenProgress = await listen<ProgressPayloadInterface>(
+    'ffmpeg-progress',
+    ({ payload }) => {
+      if (jobId === null) {
+        earlyProgress.push(payload);
+      } else if (payload.job_id === jobId) {
+        onProgress(payload);
+      }
+    }
+  );
+  const unlistenJob = await listen<FfmpegJobType>('ffmpeg-job', ({ payload }) => {
+    if (jobId === null) {
+      earlyJobs.push(payload);
+    } else if (payload.id === jobId) {
+      handleJob(payload);
+    }
+  });
+
+  try {
+    jobId = await invoke<number>('enqueue_ffmpeg_job', { kind });
+    earlyProgress.filter((payload) => payload.job_id === jobId).forEach(onProgress);
+    earlyJobs.filter((job) => job.id === jobId).forEach(handleJob);
+
+    const job = await finished;
+    if (job.status === 'cancelled') {
+      throw { kind: 'cancelled' } satisfies FfmpegError;
+    }
+    if (job.status === 'failed') {
+      throw job.error ?? ({ kind: 'other', message: 'ffmpeg job failed' } satisfies FfmpegError);
+    }
+  } finally {
+    unlistenProgress();
+    unlistenJob();
+  }
+}
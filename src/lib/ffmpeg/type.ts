@@ -1,3 +1,5 @@
+import type { FfmpegError } from './error';
+
 /** 视频信息 */
 export interface VideoInfoInterface {
   path: string;
@@ -11,9 +13,28 @@ export interface VideoInfoInterface {
  * ffmpeg 进度事件
  */
 export interface ProgressPayloadInterface {
+  job_id: number;
   progress: number;
   message: string;
   videoInfo: VideoInfoInterface;
+  /** 编码速度倍数, 1 为实时 */
+  speed: number | null;
+  /** 预计剩余时间 (秒) */
+  eta: number | null;
+  stats: FfmpegProgressInterface;
+}
+
+/**
+ * ffmpeg -progress 输出的进度块
+ */
+export interface FfmpegProgressInterface {
+  frame: number;
+  fps: number | null;
+  bitrate_kbps: number | null;
+  out_time: number;
+  speed: number | null;
+  total_size: number | null;
+  finished: boolean;
 }
 
 /**
@@ -29,6 +50,63 @@ export type VideoInfoType = {
   path: string;
   video_codec: string;
   width: number;
+  /** 显示时需要顺时针旋转的角度 */
+  rotation: number;
+  pix_fmt: string | null;
+  format_name: string;
+  format_long_name: string;
+  size: number;
+  tags: Record<string, string>;
+  streams: MediaStreamType[];
+  chapters: MediaChapterType[];
+};
+
+/**
+ * 流类型
+ */
+export type MediaStreamKind = 'video' | 'audio' | 'subtitle' | 'attachment' | 'data' | 'unknown';
+
+/**
+ * 媒体流信息
+ */
+export type MediaStreamType = {
+  index: number;
+  kind: MediaStreamKind;
+  codec_name: string;
+  codec_long_name: string;
+  profile: string | null;
+  bitrate_kbps: number | null;
+  duration: number | null;
+  language: string | null;
+  title: string | null;
+  is_default: boolean;
+  is_attached_pic: boolean;
+  tags: Record<string, string>;
+  width: number | null;
+  height: number | null;
+  fps: number | null;
+  rotation: number;
+  pix_fmt: string | null;
+  color_range: string | null;
+  color_space: string | null;
+  color_transfer: string | null;
+  color_primaries: string | null;
+  sample_rate: number | null;
+  channels: number | null;
+  channel_layout: string | null;
+  file_name: string | null;
+  mime_type: string | null;
+};
+
+/**
+ * 章节信息
+ */
+export type MediaChapterType = {
+  id: number;
+  start: number;
+  end: number;
+  title: string | null;
+  tags: Record<string, string>;
 };
 
 /**
@@ -38,3 +116,36 @@ export type VideoSegmentType = {
   start: number;
   end: number;
 };
+
+/**
+ * ffmpeg 任务类型及参数
+ */
+export type FfmpegJobKind =
+  | { type: 'convert'; videoPath: string; outputPath: string }
+  | {
+      type: 'highlight';
+      videoPath: string;
+      outputPath: string;
+      segments: { start: string; duration: string }[];
+    }
+  | { type: 'merge'; inputs: string[]; outputPath: string }
+  | { type: 'append'; basePath: string; newInputs: string[]; outputPath: string };
+
+/**
+ * ffmpeg 任务状态
+ */
+export type FfmpegJobStatus = 'pending' | 'running' | 'completed' | 'failed' | 'cancelled';
+
+/**
+ * ffmpeg 任务队列中的任务, ffmpeg-job 事件数据结构
+ */
+export type FfmpegJobType = {
+  id: number;
+  kind: FfmpegJobKind;
+  status: FfmpegJobStatus;
+  progress: number;
+  error: FfmpegError | null;
+  created_at: number;
+  started_at: number | null;
+  finished_at: number | null;
+};